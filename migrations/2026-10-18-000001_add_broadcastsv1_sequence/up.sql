@@ -0,0 +1,2 @@
+ALTER TABLE broadcastsv1
+    ADD COLUMN sequence BIGINT UNSIGNED DEFAULT 1 NOT NULL;
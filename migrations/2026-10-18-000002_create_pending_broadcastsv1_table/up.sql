@@ -0,0 +1,12 @@
+CREATE TABLE pending_broadcastsv1 (
+    id BIGINT UNSIGNED AUTO_INCREMENT NOT NULL,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    -- UTC
+    effective_at DATETIME NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY(id),
+    INDEX(effective_at),
+    INDEX(broadcaster_id, bchannel_id)
+);
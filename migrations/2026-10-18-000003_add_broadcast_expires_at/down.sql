@@ -0,0 +1,2 @@
+ALTER TABLE pending_broadcastsv1 DROP COLUMN expires_at;
+ALTER TABLE broadcastsv1 DROP COLUMN expires_at;
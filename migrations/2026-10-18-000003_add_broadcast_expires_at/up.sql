@@ -0,0 +1,6 @@
+-- UTC
+ALTER TABLE broadcastsv1
+    ADD COLUMN expires_at DATETIME NULL,
+    ADD INDEX(expires_at);
+ALTER TABLE pending_broadcastsv1
+    ADD COLUMN expires_at DATETIME NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE pending_broadcastsv1 DROP COLUMN comment, DROP COLUMN metadata;
+ALTER TABLE broadcastsv1 DROP COLUMN comment, DROP COLUMN metadata;
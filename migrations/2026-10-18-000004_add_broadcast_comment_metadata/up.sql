@@ -0,0 +1,6 @@
+ALTER TABLE broadcastsv1
+    ADD COLUMN comment VARCHAR(255) NULL,
+    ADD COLUMN metadata TEXT NULL;
+ALTER TABLE pending_broadcastsv1
+    ADD COLUMN comment VARCHAR(255) NULL,
+    ADD COLUMN metadata TEXT NULL;
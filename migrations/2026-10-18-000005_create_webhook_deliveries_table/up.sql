@@ -0,0 +1,14 @@
+CREATE TABLE webhook_deliveries (
+    id BIGINT UNSIGNED AUTO_INCREMENT NOT NULL,
+    subscriber VARCHAR(64) NOT NULL,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    version VARCHAR(200) NOT NULL,
+    sequence BIGINT UNSIGNED NOT NULL,
+    attempts INT UNSIGNED DEFAULT 0 NOT NULL,
+    -- UTC
+    next_attempt_at DATETIME NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY(id),
+    INDEX(next_attempt_at)
+);
@@ -0,0 +1,3 @@
+DROP TABLE broadcast_event_consumers;
+DROP TABLE broadcast_event_offsets;
+DROP TABLE broadcast_events;
@@ -0,0 +1,24 @@
+CREATE TABLE broadcast_events (
+    event_offset BIGINT UNSIGNED NOT NULL,
+    broadcaster_id VARCHAR(64) NOT NULL,
+    bchannel_id VARCHAR(128) NOT NULL,
+    action VARCHAR(16) NOT NULL,
+    version VARCHAR(200) NULL,
+    sequence BIGINT UNSIGNED NOT NULL,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY(event_offset),
+    INDEX(created)
+);
+-- A single row assigning event offsets: its lock serializes the commits of
+-- events so they're committed in offset order
+CREATE TABLE broadcast_event_offsets (
+    id TINYINT UNSIGNED NOT NULL,
+    last_offset BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY(id)
+);
+INSERT INTO broadcast_event_offsets (id, last_offset) VALUES (1, 0);
+CREATE TABLE broadcast_event_consumers (
+    consumer VARCHAR(64) NOT NULL,
+    acked_offset BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY(consumer)
+);
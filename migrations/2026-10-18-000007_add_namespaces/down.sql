@@ -0,0 +1,18 @@
+DELETE FROM broadcastsv1 WHERE namespace != 'default';
+DELETE FROM pending_broadcastsv1 WHERE namespace != 'default';
+DELETE FROM broadcast_events WHERE namespace != 'default';
+DELETE FROM broadcast_event_consumers WHERE namespace != 'default';
+ALTER TABLE broadcast_event_consumers
+    DROP PRIMARY KEY,
+    DROP COLUMN namespace,
+    ADD PRIMARY KEY(consumer);
+ALTER TABLE broadcast_events
+    DROP COLUMN namespace;
+ALTER TABLE pending_broadcastsv1
+    DROP INDEX namespace,
+    DROP COLUMN namespace,
+    ADD INDEX(broadcaster_id, bchannel_id);
+ALTER TABLE broadcastsv1
+    DROP PRIMARY KEY,
+    DROP COLUMN namespace,
+    ADD PRIMARY KEY(broadcaster_id, bchannel_id);
@@ -0,0 +1,14 @@
+ALTER TABLE broadcastsv1
+    ADD COLUMN namespace VARCHAR(64) DEFAULT 'default' NOT NULL FIRST,
+    DROP PRIMARY KEY,
+    ADD PRIMARY KEY(namespace, broadcaster_id, bchannel_id);
+ALTER TABLE pending_broadcastsv1
+    ADD COLUMN namespace VARCHAR(64) DEFAULT 'default' NOT NULL AFTER id,
+    DROP INDEX broadcaster_id,
+    ADD INDEX(namespace, broadcaster_id, bchannel_id);
+ALTER TABLE broadcast_events
+    ADD COLUMN namespace VARCHAR(64) DEFAULT 'default' NOT NULL AFTER event_offset;
+ALTER TABLE broadcast_event_consumers
+    ADD COLUMN namespace VARCHAR(64) DEFAULT 'default' NOT NULL AFTER consumer,
+    DROP PRIMARY KEY,
+    ADD PRIMARY KEY(consumer, namespace);
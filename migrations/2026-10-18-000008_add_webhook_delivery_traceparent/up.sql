@@ -0,0 +1,2 @@
+-- The W3C trace context of the request queueing the delivery
+ALTER TABLE webhook_deliveries ADD COLUMN traceparent VARCHAR(55) AFTER sequence;
@@ -0,0 +1,2 @@
+DELETE FROM broadcastsv1 WHERE removed;
+ALTER TABLE broadcastsv1 DROP COLUMN removed;
@@ -0,0 +1,4 @@
+-- Removed (expired) Broadcasts are kept as tombstones, retaining their
+-- sequence should they be broadcast again
+ALTER TABLE broadcastsv1
+    ADD COLUMN removed BOOLEAN DEFAULT FALSE NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE broadcastsv1 DROP INDEX namespace_expires_at;
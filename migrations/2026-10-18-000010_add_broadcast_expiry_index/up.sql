@@ -0,0 +1,3 @@
+-- Finds a namespace's next expiring Broadcast for page snapshots
+ALTER TABLE broadcastsv1
+    ADD INDEX namespace_expires_at(namespace, removed, expires_at);
@@ -0,0 +1,7 @@
+-- The last version other than ____NOP____, which monotonic version policies
+-- compare new versions against
+ALTER TABLE broadcastsv1
+    ADD COLUMN last_version VARCHAR(200) NULL;
+UPDATE broadcastsv1
+   SET last_version = version
+ WHERE version != '____NOP____';
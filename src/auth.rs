@@ -6,26 +6,50 @@
 /// Broadcasts are id'd by 'broadcaster_id/bchannel_id'. Broadcasters can only
 /// create new broadcasts under their own broadcaster_id. Readers can read all
 /// broadcasts.
-use std::collections::HashMap;
+///
+/// Users are limited to the default namespace, unless the `auth_namespaces`
+/// table in the rocket Config (keyed by user id) lists their namespaces, e.g.
+///
+/// ```toml
+/// [development.auth_namespaces]
+/// foo = ["default", "stage"]
+/// reader = ["*"]
+/// ```
+///
+/// where `*` grants every namespace.
+use std::collections::{HashMap, HashSet};
 
-use rocket::config::Value;
+use opentelemetry::trace::SpanKind;
+use rocket::config::{ConfigError, Value};
 use rocket::{Config, Request, State};
 
 use crate::db::models::{Broadcaster, Reader};
 use crate::error::{HandlerError, HandlerErrorKind, HandlerResult};
+use crate::namespace::{Namespace, DEFAULT_NAMESPACE};
+use crate::telemetry;
 
 /// Tokens mapped to an authorized id, from rocket's Config
 type AuthToken = String;
-type UserId = String;
+pub type UserId = String;
+
+/// Grants every namespace in `auth_namespaces`
+const ALL_NAMESPACES: &str = "*";
 
 /// Grouping/role of authorization
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-enum Group {
+pub enum Group {
     Broadcaster,
     Reader,
 }
 
 impl Group {
+    pub fn name(self) -> &'static str {
+        match self {
+            Group::Broadcaster => "broadcaster",
+            Group::Reader => "reader",
+        }
+    }
+
     /// Entry name in rocket Config where tokens are loaded from
     fn config_name(self) -> &'static str {
         match self {
@@ -39,6 +63,8 @@ impl Group {
 pub struct BearerTokenAuthenticator {
     users: HashMap<AuthToken, UserId>,
     groups: HashMap<UserId, Group>,
+    /// Namespaces granted to users beyond the default
+    namespaces: HashMap<UserId, HashSet<String>>,
 }
 
 impl BearerTokenAuthenticator {
@@ -46,12 +72,68 @@ impl BearerTokenAuthenticator {
         let mut authenticator = BearerTokenAuthenticator {
             users: HashMap::new(),
             groups: HashMap::new(),
+            namespaces: HashMap::new(),
         };
         authenticator.load_auth_from_config(Group::Broadcaster, config)?;
         authenticator.load_auth_from_config(Group::Reader, config)?;
+        authenticator.load_namespaces_from_config(config)?;
         Ok(authenticator)
     }
 
+    /// Load the users' granted namespaces
+    fn load_namespaces_from_config(&mut self, config: &Config) -> HandlerResult<()> {
+        let namespaces_config = match config.get_table("auth_namespaces") {
+            Ok(table) => table,
+            Err(ConfigError::Missing(_)) => return Ok(()),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_AUTH_NAMESPACES: {}",
+                e
+            )))?,
+        };
+        for (user_id, namespaces_val) in namespaces_config {
+            if !self.groups.contains_key(user_id) {
+                Err(HandlerError::internal(format!(
+                    "Invalid auth_namespaces user: {:?} (undefined)",
+                    user_id
+                )))?
+            }
+            let namespaces = namespaces_val
+                .as_array()
+                .and_then(|namespaces| {
+                    namespaces
+                        .iter()
+                        .map(|namespace| namespace.as_str().map(str::to_owned))
+                        .collect::<Option<HashSet<_>>>()
+                })
+                .ok_or_else(|| {
+                    HandlerError::internal(format!(
+                        "Invalid auth_namespaces array for: {:?}",
+                        user_id
+                    ))
+                })?;
+            if let Some(namespace) = namespaces.iter().find(|namespace| {
+                namespace.as_str() != ALL_NAMESPACES && !Namespace::is_valid(namespace)
+            }) {
+                Err(HandlerError::internal(format!(
+                    "Invalid auth_namespaces namespace for: {:?} ({:?})",
+                    user_id, namespace
+                )))?
+            }
+            self.namespaces.insert(user_id.to_string(), namespaces);
+        }
+        Ok(())
+    }
+
+    /// Determine if the user is authorized for the namespace
+    fn authorized_namespace(&self, user_id: &str, namespace: &str) -> bool {
+        match self.namespaces.get(user_id) {
+            Some(namespaces) => {
+                namespaces.contains(ALL_NAMESPACES) || namespaces.contains(namespace)
+            }
+            None => namespace == DEFAULT_NAMESPACE,
+        }
+    }
+
     /// Load the Group's auth configuration
     fn load_auth_from_config(&mut self, group: Group, config: &Config) -> HandlerResult<()> {
         let name = group.config_name();
@@ -122,45 +204,79 @@ impl BearerTokenAuthenticator {
     }
 }
 
+fn authenticator<'r>(
+    request: &'r Request<'_>,
+) -> HandlerResult<State<'r, BearerTokenAuthenticator>> {
+    request
+        .guard::<State<'_, BearerTokenAuthenticator>>()
+        .success_or(HandlerError::internal("Could not get bearer token".into()))
+}
+
+/// The user authenticated by a request (if any)
+#[derive(Clone, Debug, Default)]
+pub struct AuthenticatedUser(pub Option<(UserId, Group)>);
+
+impl AuthenticatedUser {
+    pub fn from_request(request: &Request<'_>) -> AuthenticatedUser {
+        request.local_cache(AuthenticatedUser::default).clone()
+    }
+}
+
 fn authenticated_user(request: &Request<'_>) -> HandlerResult<(UserId, Group)> {
     let credentials = request
         .headers()
         .get_one("Authorization")
         .ok_or_else(|| HandlerErrorKind::MissingAuth)?;
-    let rr = request
-        .guard::<State<'_, BearerTokenAuthenticator>>()
-        .success_or(HandlerError::internal("Could not get bearer token".into()))?
-        .authenticated_user(credentials)?;
+    let rr = authenticator(request)?.authenticated_user(credentials)?;
+    request.local_cache(|| AuthenticatedUser(Some(rr.clone())));
     Ok(rr)
 }
 
-pub fn authorized_broadcaster(request: &Request<'_>) -> HandlerResult<Broadcaster> {
-    let (id, group) = authenticated_user(request)?;
-
-    // param should be guaranteed on the path when we're called
-    let for_broadcast_id = request
-        .get_param::<String>(2)
-        .ok_or(HandlerError::internal(
-            "Could not get broadcast_id".to_owned(),
-        ))?
-        .map_err(|_| HandlerError::internal("Could not map to valid broadcast ID".to_owned()))?;
-
-    if group == Group::Broadcaster && id == for_broadcast_id {
-        // Authorized
-        Ok(Broadcaster::new(id))
+/// Return the request's Namespace if the user is authorized for it
+fn authorized_namespace(request: &Request<'_>, user_id: &str) -> HandlerResult<Namespace> {
+    let namespace = Namespace::from_request(request);
+    if authenticator(request)?.authorized_namespace(user_id, &namespace.0) {
+        Ok(namespace)
     } else {
         Err(HandlerErrorKind::Unauthorized)?
     }
 }
 
+pub fn authorized_broadcaster(request: &Request<'_>) -> HandlerResult<Broadcaster> {
+    telemetry::in_span("auth.broadcaster", SpanKind::Internal, |_| {
+        let (id, group) = authenticated_user(request)?;
+
+        // param should be guaranteed on the path when we're called
+        let for_broadcast_id = request
+            .get_param::<String>(2)
+            .ok_or(HandlerError::internal(
+                "Could not get broadcast_id".to_owned(),
+            ))?
+            .map_err(|_| {
+                HandlerError::internal("Could not map to valid broadcast ID".to_owned())
+            })?;
+
+        if group == Group::Broadcaster && id == for_broadcast_id {
+            let namespace = authorized_namespace(request, &id)?;
+            // Authorized
+            Ok(Broadcaster::new(namespace.0, id))
+        } else {
+            Err(HandlerErrorKind::Unauthorized)?
+        }
+    })
+}
+
 pub fn authorized_reader(request: &Request<'_>) -> HandlerResult<Reader> {
-    let (id, group) = authenticated_user(request)?;
-    if group == Group::Reader {
-        // Authorized
-        Ok(Reader::new(id))
-    } else {
-        Err(HandlerErrorKind::Unauthorized)?
-    }
+    telemetry::in_span("auth.reader", SpanKind::Internal, |_| {
+        let (id, group) = authenticated_user(request)?;
+        if group == Group::Reader {
+            let namespace = authorized_namespace(request, &id)?;
+            // Authorized
+            Ok(Reader::new(namespace.0, id))
+        } else {
+            Err(HandlerErrorKind::Unauthorized)?
+        }
+    })
 }
 
 #[cfg(test)]
@@ -168,6 +284,8 @@ pub(crate) mod test {
     use rocket::config::{Array, Config, Environment, Value};
     use std::collections::BTreeMap;
 
+    use crate::namespace::DEFAULT_NAMESPACE;
+
     use super::{BearerTokenAuthenticator, Group};
 
     pub(crate) fn to_table(vals: Vec<&str>) -> BTreeMap<String, Vec<Value>> {
@@ -242,4 +360,32 @@ pub(crate) mod test {
             .unwrap();
         assert!(BearerTokenAuthenticator::from_config(&config).is_err());
     }
+
+    #[test]
+    fn test_namespaces() {
+        let config = Config::build(Environment::Development)
+            .extra(
+                "broadcaster_auth",
+                to_table(["foo=bar", "baz=quux"].to_vec()),
+            )
+            .extra("reader_auth", to_table(["otto=push"].to_vec()))
+            .extra(
+                "auth_namespaces",
+                to_table(["foo=stage,test", "otto=*"].to_vec()),
+            )
+            .unwrap();
+        let authenicator = BearerTokenAuthenticator::from_config(&config).unwrap();
+        assert!(authenicator.authorized_namespace("foo", "stage"));
+        assert!(!authenicator.authorized_namespace("foo", DEFAULT_NAMESPACE));
+        assert!(authenicator.authorized_namespace("baz", DEFAULT_NAMESPACE));
+        assert!(!authenicator.authorized_namespace("baz", "stage"));
+        assert!(authenicator.authorized_namespace("otto", "anything"));
+
+        let config = Config::build(Environment::Development)
+            .extra("broadcaster_auth", to_table(["foo=bar"].to_vec()))
+            .extra("reader_auth", to_table(["otto=push"].to_vec()))
+            .extra("auth_namespaces", to_table(["nobody=stage"].to_vec()))
+            .unwrap();
+        assert!(BearerTokenAuthenticator::from_config(&config).is_err());
+    }
 }
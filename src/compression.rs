@@ -0,0 +1,206 @@
+/// Compression of JSON (and other serialized) responses
+///
+/// Negotiates a `Content-Encoding` (`br` or `gzip`) from the request's
+/// `Accept-Encoding`, compressing bodies of at least `compression_threshold`
+/// bytes from the rocket Config (default 1024). The compression ratio is
+/// recorded as the `response.compression_ratio` histogram (as a percentage of
+/// the original size).
+use std::io::{Cursor, Write};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use rocket::{
+    config::ConfigError,
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Config, Request, Response,
+};
+
+use crate::error::{HandlerError, HandlerResult};
+use crate::format::BodyFormat;
+use crate::metrics::Metrics;
+use crate::tags::Tags;
+
+/// Default minimum size of a response body to compress
+const DEFAULT_COMPRESSION_THRESHOLD: i64 = 1024;
+
+/// Brotli quality: favoring speed as responses are compressed per request
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER_SIZE,
+                    BROTLI_QUALITY,
+                    BROTLI_LG_WINDOW_SIZE,
+                );
+                writer.write_all(body)?;
+                writer.flush()?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Choose the supported Encoding with the highest quality value from an
+/// `Accept-Encoding` header, preferring `br` on ties
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut wildcard = None;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let mut qvalue = 1.0;
+        for param in params {
+            if let Some(("q", value)) = param
+                .trim()
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+            {
+                qvalue = value.parse::<f32>().unwrap_or(0.0);
+            }
+        }
+        match name.as_str() {
+            "br" => brotli = Some(qvalue),
+            "gzip" | "x-gzip" => gzip = Some(qvalue),
+            "*" => wildcard = Some(qvalue),
+            _ => (),
+        }
+    }
+    let brotli = brotli.or(wildcard).unwrap_or(0.0);
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    if brotli <= 0.0 && gzip <= 0.0 {
+        None
+    } else if brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else {
+        Some(Encoding::Gzip)
+    }
+}
+
+pub struct Compression {
+    threshold: usize,
+    metrics: Metrics,
+}
+
+impl Compression {
+    pub fn from_config(config: &Config, metrics: Metrics) -> HandlerResult<Compression> {
+        let threshold = match config.get_int("compression_threshold") {
+            Ok(threshold) if threshold >= 0 => threshold,
+            Err(ConfigError::Missing(_)) => DEFAULT_COMPRESSION_THRESHOLD,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_COMPRESSION_THRESHOLD".to_owned(),
+            ))?,
+        };
+        Ok(Compression {
+            threshold: threshold as usize,
+            metrics,
+        })
+    }
+}
+
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let compressible = response.content_type().map_or(false, |ct| {
+            ct.is_json() || ct.is_msgpack() || ct == BodyFormat::Cbor.content_type()
+        });
+        if !compressible || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        let encoding = match request
+            .headers()
+            .get_one("Accept-Encoding")
+            .and_then(negotiate)
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+        if body.is_empty() || body.len() < self.threshold {
+            response.set_sized_body(Cursor::new(body));
+            return;
+        }
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                let mut tags = Tags::default();
+                tags.tags
+                    .insert("encoding".to_owned(), encoding.name().to_owned());
+                self.metrics.histogram_with_tags(
+                    "response.compression_ratio",
+                    (compressed.len() * 100 / body.len()) as u64,
+                    Some(tags),
+                );
+                response.set_header(Header::new("Content-Encoding", encoding.name()));
+                response.set_sized_body(Cursor::new(compressed));
+            }
+            // Fallback to the uncompressed body
+            Err(_) => response.set_sized_body(Cursor::new(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate, Encoding};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZIP;q=0.8, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0, br;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let body = b"{\"broadcasts\": {}}".repeat(100);
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let compressed = encoding.compress(&body).unwrap();
+            assert!(compressed.len() < body.len());
+        }
+    }
+}
@@ -0,0 +1,5 @@
+INSERT INTO broadcast_event_consumers
+    (consumer, namespace, acked_offset)
+VALUES (?, ?, ?)
+ON DUPLICATE KEY UPDATE
+    acked_offset = GREATEST(acked_offset, VALUES(acked_offset));
@@ -2,35 +2,598 @@
 
 use std::collections::HashMap;
 
-use diesel::mysql::MysqlConnection;
-use diesel::sql_types::Text;
-use diesel::{sql_query, QueryDsl, RunQueryDsl};
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::{And, Eq, Gt, IsNull, Or};
+use diesel::sql_types::{Bigint, Datetime, Nullable, Text, Unsigned};
+use diesel::{
+    insert_into, sql_query, BoolExpressionMethods, Connection, ExpressionMethods,
+    OptionalExtension, QueryDsl, RunQueryDsl,
+};
 
-use super::schema::broadcastsv1;
-use crate::error::{HandlerErrorKind, HandlerResult};
+use super::schema::{
+    broadcast_event_consumers, broadcast_event_offsets, broadcast_events, broadcastsv1,
+    pending_broadcastsv1, webhook_deliveries,
+};
+use super::TracedConnection;
+use crate::error::{HandlerError, HandlerErrorKind, HandlerResult};
+use crate::telemetry;
+use crate::version_policy::{VersionPolicies, NOP_VERSION};
+use crate::webhooks::Webhooks;
 
 #[derive(Debug, Queryable, Insertable)]
 #[table_name = "broadcastsv1"]
 pub struct Broadcast {
+    pub namespace: String,
     pub broadcaster_id: String,
     pub bchannel_id: String,
     pub version: String,
+    pub sequence: u64,
+    /// UTC
+    pub created: NaiveDateTime,
+    /// UTC
+    pub last_updated: NaiveDateTime,
+    /// UTC
+    pub expires_at: Option<NaiveDateTime>,
+    pub comment: Option<String>,
+    /// Serialized JSON object
+    pub metadata: Option<String>,
 }
 
 impl Broadcast {
     pub fn id(&self) -> String {
         format!("{}/{}", self.broadcaster_id, self.bchannel_id)
     }
+
+    /// Expire all Broadcasts past their `expires_at`
+    ///
+    /// Broadcasts with an entry in `fallbacks` (keyed by Broadcast id) are
+    /// reverted to its fallback version, otherwise they're removed.
+    ///
+    /// Expired Broadcasts are already hidden from readers, this records
+    /// their removal. Removed Broadcasts remain as tombstones so their
+    /// sequence continues if they're broadcast again.
+    pub fn expire_due(
+        conn: &TracedConnection,
+        fallbacks: &HashMap<String, String>,
+        webhooks: &Webhooks,
+    ) -> HandlerResult<Vec<(Broadcast, Expiration)>> {
+        conn.transaction(|| {
+            let due = broadcastsv1::table
+                .select(BROADCAST_COLUMNS)
+                .filter(broadcastsv1::removed.eq(false))
+                .filter(broadcastsv1::expires_at.le(Utc::now().naive_utc()))
+                .for_update()
+                .load::<Broadcast>(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let mut expired = Vec::with_capacity(due.len());
+            for bcast in due {
+                let expiration = if let Some(fallback) = fallbacks.get(&bcast.id()) {
+                    let new_version = NewVersion {
+                        version: fallback.to_owned(),
+                        ..Default::default()
+                    };
+                    Broadcaster::new(bcast.namespace.clone(), bcast.broadcaster_id.clone())
+                        .broadcast_new_version(conn, &bcast.bchannel_id, &new_version, webhooks)?;
+                    Expiration::Reverted(fallback.to_owned())
+                } else {
+                    diesel::update(broadcastsv1::table.find((
+                        &bcast.namespace,
+                        &bcast.broadcaster_id,
+                        &bcast.bchannel_id,
+                    )))
+                    .set(broadcastsv1::removed.eq(true))
+                    .execute(conn)
+                    .map_err(HandlerErrorKind::DBError)?;
+                    BroadcastEvent::record(
+                        conn,
+                        &bcast.namespace,
+                        &bcast.broadcaster_id,
+                        &bcast.bchannel_id,
+                        EventAction::Remove,
+                        None,
+                        bcast.sequence,
+                    )?;
+                    Expiration::Removed
+                };
+                expired.push((bcast, expiration));
+            }
+            Ok(expired)
+        })
+    }
+}
+
+const BROADCAST_COLUMNS: (
+    broadcastsv1::namespace,
+    broadcastsv1::broadcaster_id,
+    broadcastsv1::bchannel_id,
+    broadcastsv1::version,
+    broadcastsv1::sequence,
+    broadcastsv1::created,
+    broadcastsv1::last_updated,
+    broadcastsv1::expires_at,
+    broadcastsv1::comment,
+    broadcastsv1::metadata,
+) = (
+    broadcastsv1::namespace,
+    broadcastsv1::broadcaster_id,
+    broadcastsv1::bchannel_id,
+    broadcastsv1::version,
+    broadcastsv1::sequence,
+    broadcastsv1::created,
+    broadcastsv1::last_updated,
+    broadcastsv1::expires_at,
+    broadcastsv1::comment,
+    broadcastsv1::metadata,
+);
+
+/// Filter of the current Broadcasts: neither removed nor past their
+/// `expires_at`
+type IsCurrent = And<
+    Eq<broadcastsv1::removed, bool>,
+    Or<IsNull<broadcastsv1::expires_at>, Gt<broadcastsv1::expires_at, NaiveDateTime>>,
+>;
+
+fn is_current() -> IsCurrent {
+    broadcastsv1::removed.eq(false).and(
+        broadcastsv1::expires_at
+            .is_null()
+            .or(broadcastsv1::expires_at.gt(Utc::now().naive_utc())),
+    )
+}
+
+/// How an expired Broadcast was handled
+#[derive(Debug, Eq, PartialEq)]
+pub enum Expiration {
+    /// Removed entirely
+    Removed,
+    /// Reverted to the contained fallback version
+    Reverted(String),
+}
+
+/// A new version to broadcast
+#[derive(Debug, Default)]
+pub struct NewVersion {
+    pub version: String,
+    /// UTC
+    pub expires_at: Option<NaiveDateTime>,
+    pub comment: Option<String>,
+    /// Serialized JSON object
+    pub metadata: Option<String>,
+}
+
+no_arg_sql_function!(
+    last_insert_id,
+    Unsigned<Bigint>,
+    "The AUTO_INCREMENT id of the last inserted row"
+);
+
+/// A version scheduled to take effect at a future time
+#[derive(Debug, Queryable)]
+pub struct PendingBroadcast {
+    pub id: u64,
+    pub namespace: String,
+    pub broadcaster_id: String,
+    pub bchannel_id: String,
+    pub version: String,
+    /// UTC
+    pub effective_at: NaiveDateTime,
+    /// UTC
+    pub expires_at: Option<NaiveDateTime>,
+    pub comment: Option<String>,
+    /// Serialized JSON object
+    pub metadata: Option<String>,
+}
+
+impl PendingBroadcast {
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.broadcaster_id, self.bchannel_id)
+    }
+
+    /// Promote all PendingBroadcasts that are now due into broadcastsv1
+    ///
+    /// Each version is checked against its broadcaster's VersionPolicy (and
+    /// the then current version) once more, as when it was scheduled.
+    /// PendingBroadcasts failing the check are dropped instead.
+    ///
+    /// Returns the due PendingBroadcasts and whether each was promoted.
+    pub fn promote_due(
+        conn: &TracedConnection,
+        policies: &VersionPolicies,
+        webhooks: &Webhooks,
+    ) -> HandlerResult<Vec<(PendingBroadcast, Promotion)>> {
+        conn.transaction(|| {
+            let due = pending_broadcastsv1::table
+                .select(PENDING_COLUMNS)
+                .filter(pending_broadcastsv1::effective_at.le(Utc::now().naive_utc()))
+                .order((pending_broadcastsv1::effective_at, pending_broadcastsv1::id))
+                .for_update()
+                .load::<PendingBroadcast>(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let mut promoted = Vec::with_capacity(due.len());
+            for pending in due {
+                let broadcaster =
+                    Broadcaster::new(pending.namespace.clone(), pending.broadcaster_id.clone());
+                let promotion = match broadcaster.check_version_policy(
+                    conn,
+                    policies,
+                    &pending.bchannel_id,
+                    &pending.version,
+                ) {
+                    Ok(()) => {
+                        let new_version = NewVersion {
+                            version: pending.version.clone(),
+                            expires_at: pending.expires_at,
+                            comment: pending.comment.clone(),
+                            metadata: pending.metadata.clone(),
+                        };
+                        broadcaster.broadcast_new_version(
+                            conn,
+                            &pending.bchannel_id,
+                            &new_version,
+                            webhooks,
+                        )?;
+                        Promotion::Promoted
+                    }
+                    Err(e) => match e.kind() {
+                        HandlerErrorKind::VersionPolicyError(_)
+                        | HandlerErrorKind::VersionRegressionError(..) => Promotion::Rejected(e),
+                        _ => Err(e)?,
+                    },
+                };
+                diesel::delete(pending_broadcastsv1::table.find(pending.id))
+                    .execute(conn)
+                    .map_err(HandlerErrorKind::DBError)?;
+                promoted.push((pending, promotion));
+            }
+            Ok(promoted)
+        })
+    }
+}
+
+/// How a due PendingBroadcast was handled
+#[derive(Debug)]
+pub enum Promotion {
+    /// Broadcast as the new version
+    Promoted,
+    /// Dropped, failing its broadcaster's VersionPolicy
+    Rejected(HandlerError),
+}
+
+const PENDING_COLUMNS: (
+    pending_broadcastsv1::id,
+    pending_broadcastsv1::namespace,
+    pending_broadcastsv1::broadcaster_id,
+    pending_broadcastsv1::bchannel_id,
+    pending_broadcastsv1::version,
+    pending_broadcastsv1::effective_at,
+    pending_broadcastsv1::expires_at,
+    pending_broadcastsv1::comment,
+    pending_broadcastsv1::metadata,
+) = (
+    pending_broadcastsv1::id,
+    pending_broadcastsv1::namespace,
+    pending_broadcastsv1::broadcaster_id,
+    pending_broadcastsv1::bchannel_id,
+    pending_broadcastsv1::version,
+    pending_broadcastsv1::effective_at,
+    pending_broadcastsv1::expires_at,
+    pending_broadcastsv1::comment,
+    pending_broadcastsv1::metadata,
+);
+
+/// The kind of a BroadcastEvent
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventAction {
+    /// A new version was broadcast
+    Update,
+    /// The Broadcast was removed
+    Remove,
+}
+
+impl EventAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventAction::Update => "update",
+            EventAction::Remove => "remove",
+        }
+    }
+}
+
+/// A change to a Broadcast, in the change log ordered by `offset`
+#[derive(Debug, Queryable)]
+pub struct BroadcastEvent {
+    pub offset: u64,
+    pub namespace: String,
+    pub broadcaster_id: String,
+    pub bchannel_id: String,
+    /// An EventAction
+    pub action: String,
+    /// The new version (None when removed)
+    pub version: Option<String>,
+    pub sequence: u64,
+    /// UTC
+    pub created: NaiveDateTime,
+}
+
+impl BroadcastEvent {
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.broadcaster_id, self.bchannel_id)
+    }
+
+    /// Append an event to the change log
+    ///
+    /// Should be called within the transaction of the change. Assigning the
+    /// offset locks the offsets' row until the transaction completes, so
+    /// events are committed in offset order (consumers never observe a gap
+    /// filled in later).
+    ///
+    /// Returns the event's offset.
+    pub fn record(
+        conn: &TracedConnection,
+        namespace: &str,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        action: EventAction,
+        version: Option<&str>,
+        sequence: u64,
+    ) -> HandlerResult<u64> {
+        conn.transaction(|| {
+            let offsets = broadcast_event_offsets::table.find(1);
+            sql_query(include_str!("next_event_offset.sql"))
+                .execute(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let offset = offsets
+                .select(broadcast_event_offsets::last_offset)
+                .first(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            insert_into(broadcast_events::table)
+                .values((
+                    broadcast_events::event_offset.eq(offset),
+                    broadcast_events::namespace.eq(namespace),
+                    broadcast_events::broadcaster_id.eq(broadcaster_id),
+                    broadcast_events::bchannel_id.eq(bchannel_id),
+                    broadcast_events::action.eq(action.as_str()),
+                    broadcast_events::version.eq(version),
+                    broadcast_events::sequence.eq(sequence),
+                ))
+                .execute(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            Ok(offset)
+        })
+    }
+
+    /// Remove events created before `before` (UTC) that every consumer of
+    /// their namespace has acknowledged
+    ///
+    /// Events remain until then however old, so consumers never miss
+    /// events while offline.
+    ///
+    /// Returns the number of events removed.
+    pub fn prune(conn: &TracedConnection, before: NaiveDateTime) -> HandlerResult<usize> {
+        Ok(sql_query(include_str!("prune_events.sql"))
+            .bind::<Datetime, _>(before)
+            .execute(conn)
+            .map_err(HandlerErrorKind::DBError)?)
+    }
 }
 
-/// An authorized broadcaster
+/// A queued webhook notification of a new version
+#[derive(Debug, Queryable)]
+pub struct WebhookDelivery {
+    pub id: u64,
+    pub subscriber: String,
+    pub broadcaster_id: String,
+    pub bchannel_id: String,
+    pub version: String,
+    pub sequence: u64,
+    /// The `traceparent` of the request queueing the delivery
+    pub traceparent: Option<String>,
+    /// Number of failed delivery attempts
+    pub attempts: u32,
+    /// UTC
+    pub next_attempt_at: NaiveDateTime,
+}
+
+impl WebhookDelivery {
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.broadcaster_id, self.bchannel_id)
+    }
+
+    /// Queue a new version's delivery to each of the `subscribers`, within
+    /// the current trace
+    pub fn enqueue(
+        conn: &TracedConnection,
+        subscribers: &[&str],
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        version: &str,
+        sequence: u64,
+    ) -> HandlerResult<()> {
+        let now = Utc::now().naive_utc();
+        let traceparent = telemetry::traceparent();
+        let rows: Vec<_> = subscribers
+            .iter()
+            .map(|subscriber| {
+                (
+                    webhook_deliveries::subscriber.eq(subscriber),
+                    webhook_deliveries::broadcaster_id.eq(broadcaster_id),
+                    webhook_deliveries::bchannel_id.eq(bchannel_id),
+                    webhook_deliveries::version.eq(version),
+                    webhook_deliveries::sequence.eq(sequence),
+                    webhook_deliveries::traceparent.eq(&traceparent),
+                    webhook_deliveries::next_attempt_at.eq(now),
+                )
+            })
+            .collect();
+        insert_into(webhook_deliveries::table)
+            .values(&rows)
+            .execute(conn)
+            .map_err(HandlerErrorKind::DBError)?;
+        Ok(())
+    }
+
+    /// Claim up to `limit` due deliveries, leasing them until `lease_until`
+    /// (UTC)
+    ///
+    /// Deliveries are claimed by advancing their `next_attempt_at`, so
+    /// concurrent callers never claim the same delivery and those abandoned
+    /// (e.g. by a restart) become due again after their lease.
+    pub fn claim_due(
+        conn: &TracedConnection,
+        limit: i64,
+        lease_until: NaiveDateTime,
+    ) -> HandlerResult<Vec<WebhookDelivery>> {
+        let due = webhook_deliveries::table
+            .select(WEBHOOK_DELIVERY_COLUMNS)
+            .filter(webhook_deliveries::next_attempt_at.le(Utc::now().naive_utc()))
+            .order(webhook_deliveries::id)
+            .limit(limit)
+            .load::<WebhookDelivery>(conn)
+            .map_err(HandlerErrorKind::DBError)?;
+        let mut claimed = Vec::with_capacity(due.len());
+        for mut delivery in due {
+            let affected_rows = diesel::update(
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::id.eq(delivery.id))
+                    .filter(webhook_deliveries::next_attempt_at.eq(delivery.next_attempt_at)),
+            )
+            .set(webhook_deliveries::next_attempt_at.eq(lease_until))
+            .execute(conn)
+            .map_err(HandlerErrorKind::DBError)?;
+            if affected_rows == 1 {
+                delivery.next_attempt_at = lease_until;
+                claimed.push(delivery);
+            }
+        }
+        Ok(claimed)
+    }
+
+    /// Remove a delivery after it succeeded or was abandoned
+    pub fn remove(&self, conn: &TracedConnection) -> HandlerResult<()> {
+        diesel::delete(webhook_deliveries::table.find(self.id))
+            .execute(conn)
+            .map_err(HandlerErrorKind::DBError)?;
+        Ok(())
+    }
+
+    /// Record a failed attempt, retrying at `next_attempt_at` (UTC)
+    pub fn retry_at(
+        &self,
+        conn: &TracedConnection,
+        next_attempt_at: NaiveDateTime,
+    ) -> HandlerResult<()> {
+        diesel::update(webhook_deliveries::table.find(self.id))
+            .set((
+                webhook_deliveries::attempts.eq(self.attempts + 1),
+                webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+            ))
+            .execute(conn)
+            .map_err(HandlerErrorKind::DBError)?;
+        Ok(())
+    }
+}
+
+const WEBHOOK_DELIVERY_COLUMNS: (
+    webhook_deliveries::id,
+    webhook_deliveries::subscriber,
+    webhook_deliveries::broadcaster_id,
+    webhook_deliveries::bchannel_id,
+    webhook_deliveries::version,
+    webhook_deliveries::sequence,
+    webhook_deliveries::traceparent,
+    webhook_deliveries::attempts,
+    webhook_deliveries::next_attempt_at,
+) = (
+    webhook_deliveries::id,
+    webhook_deliveries::subscriber,
+    webhook_deliveries::broadcaster_id,
+    webhook_deliveries::bchannel_id,
+    webhook_deliveries::version,
+    webhook_deliveries::sequence,
+    webhook_deliveries::traceparent,
+    webhook_deliveries::attempts,
+    webhook_deliveries::next_attempt_at,
+);
+
+/// The outcome of broadcasting a new version
+#[derive(Debug)]
+pub struct BroadcastUpdate {
+    /// Whether this Broadcast did not previously have a current version
+    pub created: bool,
+    /// The Broadcast's sequence number, incremented on every new version
+    pub sequence: u64,
+}
+
+/// An authorized broadcaster (within a namespace)
 pub struct Broadcaster {
     pub id: String,
+    pub namespace: String,
 }
 
 impl Broadcaster {
-    pub fn new(id: String) -> Broadcaster {
-        Broadcaster { id }
+    pub fn new(namespace: String, id: String) -> Broadcaster {
+        Broadcaster { id, namespace }
+    }
+
+    /// Return the current version of a Broadcast (if any)
+    ///
+    /// Removed or expired Broadcasts have no current version. Locks the
+    /// Broadcast's row for the remainder of the transaction.
+    pub fn current_version(
+        &self,
+        conn: &TracedConnection,
+        bchannel_id: &str,
+    ) -> HandlerResult<Option<String>> {
+        Ok(broadcastsv1::table
+            .select(broadcastsv1::version)
+            .filter(broadcastsv1::namespace.eq(&self.namespace))
+            .filter(broadcastsv1::broadcaster_id.eq(&self.id))
+            .filter(broadcastsv1::bchannel_id.eq(bchannel_id))
+            .filter(is_current())
+            .for_update()
+            .first(conn)
+            .optional()
+            .map_err(HandlerErrorKind::DBError)?)
+    }
+
+    /// Check a new version against the broadcaster's VersionPolicy (if any)
+    ///
+    /// Should be called within the transaction storing the version.
+    pub fn check_version_policy(
+        &self,
+        conn: &TracedConnection,
+        policies: &VersionPolicies,
+        bchannel_id: &str,
+        version: &str,
+    ) -> HandlerResult<()> {
+        if let Some(policy) = policies.get(&self.id) {
+            let last = self.last_version(conn, bchannel_id)?;
+            policy.check(version, last.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Return the last version of a Broadcast other than the NOP_VERSION
+    /// (if any), so versions can't regress by way of the NOP_VERSION
+    ///
+    /// Removed or expired Broadcasts have no last version. Locks the
+    /// Broadcast's row for the remainder of the transaction.
+    fn last_version(
+        &self,
+        conn: &TracedConnection,
+        bchannel_id: &str,
+    ) -> HandlerResult<Option<String>> {
+        Ok(broadcastsv1::table
+            .select(broadcastsv1::last_version)
+            .filter(broadcastsv1::namespace.eq(&self.namespace))
+            .filter(broadcastsv1::broadcaster_id.eq(&self.id))
+            .filter(broadcastsv1::bchannel_id.eq(bchannel_id))
+            .filter(is_current())
+            .for_update()
+            .first::<Option<String>>(conn)
+            .optional()
+            .map_err(HandlerErrorKind::DBError)?
+            .flatten())
     }
 
     /// Broadcast a new version
@@ -39,51 +602,314 @@ impl Broadcaster {
     ///
     /// Err(HandlerError) on failure.
     ///
-    /// Ok(true) if this Broadcast did not have a current version and one was
-    /// successfully created.
+    /// Ok(BroadcastUpdate) with `created` set if this Broadcast did not have
+    /// a current version and one was successfully created, unset if it had an
+    /// existing version that was successfully modified to the new version.
+    /// Its `sequence` is the Broadcast's newly assigned sequence number.
     ///
-    /// Ok(false) if this Broadcast had an existing version that was
-    /// successfully modified to the new version.
+    /// Queues notifications of the new version to the matching `webhooks`
+    /// subscribers within the same transaction.
     pub fn broadcast_new_version(
         &self,
-        conn: &MysqlConnection,
+        conn: &TracedConnection,
         bchannel_id: &str,
-        version: &str,
-    ) -> HandlerResult<bool> {
-        let affected_rows = sql_query(include_str!("upsert_broadcast.sql"))
-            .bind::<Text, _>(&self.id)
-            .bind::<Text, _>(bchannel_id)
-            .bind::<Text, _>(version)
-            .bind::<Text, _>(version)
-            .execute(conn)
-            .map_err(HandlerErrorKind::DBError)?;
+        new_version: &NewVersion,
+        webhooks: &Webhooks,
+    ) -> HandlerResult<BroadcastUpdate> {
+        conn.transaction(|| {
+            let created = self.current_version(conn, bchannel_id)?.is_none();
+            sql_query(include_str!("upsert_broadcast.sql"))
+                .bind::<Text, _>(&self.namespace)
+                .bind::<Text, _>(&self.id)
+                .bind::<Text, _>(bchannel_id)
+                .bind::<Text, _>(&new_version.version)
+                .bind::<Text, _>(&new_version.version)
+                .bind::<Text, _>(NOP_VERSION)
+                .bind::<Nullable<Datetime>, _>(new_version.expires_at)
+                .bind::<Nullable<Text>, _>(&new_version.comment)
+                .bind::<Nullable<Text>, _>(&new_version.metadata)
+                .bind::<Text, _>(&new_version.version)
+                .bind::<Nullable<Datetime>, _>(new_version.expires_at)
+                .bind::<Nullable<Text>, _>(&new_version.comment)
+                .bind::<Nullable<Text>, _>(&new_version.metadata)
+                .execute(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let sequence = broadcastsv1::table
+                .select(broadcastsv1::sequence)
+                .filter(broadcastsv1::namespace.eq(&self.namespace))
+                .filter(broadcastsv1::broadcaster_id.eq(&self.id))
+                .filter(broadcastsv1::bchannel_id.eq(bchannel_id))
+                .first(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            BroadcastEvent::record(
+                conn,
+                &self.namespace,
+                &self.id,
+                bchannel_id,
+                EventAction::Update,
+                Some(&new_version.version),
+                sequence,
+            )?;
+            webhooks.enqueue(
+                conn,
+                &self.namespace,
+                &self.id,
+                bchannel_id,
+                &new_version.version,
+                sequence,
+            )?;
+            Ok(BroadcastUpdate { created, sequence })
+        })
+    }
+
+    /// Schedule a new version to take effect at `effective_at` (UTC)
+    ///
+    /// Returns the id of the new PendingBroadcast.
+    pub fn schedule_new_version(
+        &self,
+        conn: &TracedConnection,
+        bchannel_id: &str,
+        new_version: &NewVersion,
+        effective_at: NaiveDateTime,
+    ) -> HandlerResult<u64> {
+        conn.transaction(|| {
+            insert_into(pending_broadcastsv1::table)
+                .values((
+                    pending_broadcastsv1::namespace.eq(&self.namespace),
+                    pending_broadcastsv1::broadcaster_id.eq(&self.id),
+                    pending_broadcastsv1::bchannel_id.eq(bchannel_id),
+                    pending_broadcastsv1::version.eq(&new_version.version),
+                    pending_broadcastsv1::effective_at.eq(effective_at),
+                    pending_broadcastsv1::expires_at.eq(new_version.expires_at),
+                    pending_broadcastsv1::comment.eq(&new_version.comment),
+                    pending_broadcastsv1::metadata.eq(&new_version.metadata),
+                ))
+                .execute(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            Ok(diesel::select(last_insert_id)
+                .first(conn)
+                .map_err(HandlerErrorKind::DBError)?)
+        })
+    }
+
+    /// Return this broadcaster's PendingBroadcasts, ordered by when they take
+    /// effect
+    pub fn pending_versions(
+        &self,
+        conn: &TracedConnection,
+    ) -> HandlerResult<Vec<PendingBroadcast>> {
+        Ok(pending_broadcastsv1::table
+            .select(PENDING_COLUMNS)
+            .filter(pending_broadcastsv1::namespace.eq(&self.namespace))
+            .filter(pending_broadcastsv1::broadcaster_id.eq(&self.id))
+            .order((pending_broadcastsv1::effective_at, pending_broadcastsv1::id))
+            .load(conn)
+            .map_err(HandlerErrorKind::DBError)?)
+    }
+
+    /// Cancel one of this broadcaster's PendingBroadcasts
+    ///
+    /// Returns whether the PendingBroadcast existed.
+    pub fn cancel_pending_version(&self, conn: &TracedConnection, id: u64) -> HandlerResult<bool> {
+        let affected_rows = diesel::delete(
+            pending_broadcastsv1::table
+                .filter(pending_broadcastsv1::id.eq(id))
+                .filter(pending_broadcastsv1::namespace.eq(&self.namespace))
+                .filter(pending_broadcastsv1::broadcaster_id.eq(&self.id)),
+        )
+        .execute(conn)
+        .map_err(HandlerErrorKind::DBError)?;
         Ok(affected_rows == 1)
     }
 }
 
-/// An authorized reader of broadcasts
+/// Marker of a namespace's Broadcasts, changing whenever any of them is
+/// added, updated or removed (all of which record a BroadcastEvent) or
+/// expires
+///
+/// Cheap to read: it doesn't aggregate over the Broadcasts.
+#[derive(Debug, QueryableByName)]
+struct Snapshot {
+    /// The last BroadcastEvent's offset (of any namespace)
+    #[sql_type = "Unsigned<Bigint>"]
+    last_offset: u64,
+    /// UTC
+    #[sql_type = "Nullable<Datetime>"]
+    next_expiry: Option<NaiveDateTime>,
+}
+
+/// A page of Broadcasts
+#[derive(Debug)]
+pub struct BroadcastPage {
+    pub broadcasts: Vec<Broadcast>,
+    /// Opaque marker of the Broadcasts when the page was read
+    pub snapshot: String,
+    /// Whether there are further Broadcasts following this page
+    pub more: bool,
+}
+
+/// An authorized reader of broadcasts (within a namespace)
 pub struct Reader {
     pub id: String,
+    pub namespace: String,
 }
 
 impl Reader {
-    pub fn new(id: String) -> Reader {
-        Reader { id }
+    pub fn new(namespace: String, id: String) -> Reader {
+        Reader { id, namespace }
     }
 
-    pub fn read_broadcasts(
+    /// Return the offset of the last BroadcastEvent this reader acknowledged
+    /// (0 if none)
+    pub fn acked_offset(&self, conn: &TracedConnection) -> HandlerResult<u64> {
+        Ok(broadcast_event_consumers::table
+            .select(broadcast_event_consumers::acked_offset)
+            .filter(broadcast_event_consumers::consumer.eq(&self.id))
+            .filter(broadcast_event_consumers::namespace.eq(&self.namespace))
+            .first(conn)
+            .optional()
+            .map_err(HandlerErrorKind::DBError)?
+            .unwrap_or(0))
+    }
+
+    /// Read up to `limit` BroadcastEvents following the `after` offset
+    pub fn read_events(
         &self,
-        conn: &MysqlConnection,
-    ) -> HandlerResult<HashMap<String, String>> {
-        // flatten into HashMap FromIterator<(K, V)>
-        Ok(broadcastsv1::table
+        conn: &TracedConnection,
+        after: u64,
+        limit: u32,
+    ) -> HandlerResult<Vec<BroadcastEvent>> {
+        Ok(broadcast_events::table
             .select((
-                broadcastsv1::broadcaster_id,
-                broadcastsv1::bchannel_id,
-                broadcastsv1::version,
+                broadcast_events::event_offset,
+                broadcast_events::namespace,
+                broadcast_events::broadcaster_id,
+                broadcast_events::bchannel_id,
+                broadcast_events::action,
+                broadcast_events::version,
+                broadcast_events::sequence,
+                broadcast_events::created,
             ))
+            .filter(broadcast_events::namespace.eq(&self.namespace))
+            .filter(broadcast_events::event_offset.gt(after))
+            .order(broadcast_events::event_offset)
+            .limit(i64::from(limit))
+            .load(conn)
+            .map_err(HandlerErrorKind::DBError)?)
+    }
+
+    /// Acknowledge the BroadcastEvents up to (and including) `offset`
+    ///
+    /// Acknowledgements never move backwards. Returns the resulting acked
+    /// offset.
+    pub fn ack_events(&self, conn: &TracedConnection, offset: u64) -> HandlerResult<u64> {
+        conn.transaction(|| {
+            let last_offset: u64 = broadcast_event_offsets::table
+                .find(1)
+                .select(broadcast_event_offsets::last_offset)
+                .first(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            if offset > last_offset {
+                Err(HandlerErrorKind::InvalidParameter(format!(
+                    "offset must not exceed the last event's offset ({})",
+                    last_offset
+                )))?
+            }
+            sql_query(include_str!("ack_events.sql"))
+                .bind::<Text, _>(&self.id)
+                .bind::<Text, _>(&self.namespace)
+                .bind::<Unsigned<Bigint>, _>(offset)
+                .execute(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            self.acked_offset(conn)
+        })
+    }
+
+    /// Read all current Broadcasts of the reader's namespace
+    pub fn read_broadcast_rows(&self, conn: &TracedConnection) -> HandlerResult<Vec<Broadcast>> {
+        Ok(broadcastsv1::table
+            .select(BROADCAST_COLUMNS)
+            .filter(broadcastsv1::namespace.eq(&self.namespace))
+            .filter(is_current())
             .load::<Broadcast>(conn)
-            .map_err(HandlerErrorKind::DBError)?
+            .map_err(HandlerErrorKind::DBError)?)
+    }
+
+    /// Read a single Broadcast (if it exists)
+    pub fn read_broadcast(
+        &self,
+        conn: &TracedConnection,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+    ) -> HandlerResult<Option<Broadcast>> {
+        Ok(broadcastsv1::table
+            .select(BROADCAST_COLUMNS)
+            .filter(broadcastsv1::namespace.eq(&self.namespace))
+            .filter(broadcastsv1::broadcaster_id.eq(broadcaster_id))
+            .filter(broadcastsv1::bchannel_id.eq(bchannel_id))
+            .filter(is_current())
+            .first(conn)
+            .optional()
+            .map_err(HandlerErrorKind::DBError)?)
+    }
+
+    /// Read a page of at most `limit` Broadcasts ordered by id, starting
+    /// after the `after` (broadcaster_id, bchannel_id) key
+    pub fn read_broadcast_page(
+        &self,
+        conn: &TracedConnection,
+        after: Option<(&str, &str)>,
+        limit: u32,
+    ) -> HandlerResult<BroadcastPage> {
+        conn.transaction(|| {
+            // Read the snapshot within the same transaction as the page
+            // (whose consistent read it shares)
+            let snapshot = sql_query(include_str!("snapshot.sql"))
+                .bind::<Text, _>(&self.namespace)
+                .bind::<Datetime, _>(Utc::now().naive_utc())
+                .get_result::<Snapshot>(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let mut query = broadcastsv1::table
+                .select(BROADCAST_COLUMNS)
+                .filter(broadcastsv1::namespace.eq(&self.namespace))
+                .filter(is_current())
+                .order((broadcastsv1::broadcaster_id, broadcastsv1::bchannel_id))
+                // One extra row determines whether there's a following page
+                .limit(i64::from(limit) + 1)
+                .into_boxed();
+            if let Some((broadcaster_id, bchannel_id)) = after {
+                query = query.filter(
+                    broadcastsv1::broadcaster_id.gt(broadcaster_id).or(
+                        broadcastsv1::broadcaster_id
+                            .eq(broadcaster_id)
+                            .and(broadcastsv1::bchannel_id.gt(bchannel_id)),
+                    ),
+                );
+            }
+            let mut broadcasts = query
+                .load::<Broadcast>(conn)
+                .map_err(HandlerErrorKind::DBError)?;
+            let more = broadcasts.len() > limit as usize;
+            broadcasts.truncate(limit as usize);
+            Ok(BroadcastPage {
+                broadcasts,
+                snapshot: format!(
+                    "{}-{}",
+                    snapshot.last_offset,
+                    snapshot.next_expiry.map_or(0, |at| at.timestamp())
+                ),
+                more,
+            })
+        })
+    }
+
+    pub fn read_broadcasts(
+        &self,
+        conn: &TracedConnection,
+    ) -> HandlerResult<HashMap<String, String>> {
+        // flatten into HashMap FromIterator<(K, V)>
+        Ok(self
+            .read_broadcast_rows(conn)?
             .into_iter()
             .map(|bcast| (bcast.id(), bcast.version))
             .collect())
@@ -0,0 +1,3 @@
+UPDATE broadcast_event_offsets
+   SET last_offset = last_offset + 1
+ WHERE id = 1
@@ -0,0 +1,7 @@
+DELETE FROM broadcast_events
+ WHERE created < ?
+   AND event_offset <= COALESCE(
+           (SELECT MIN(acked_offset)
+              FROM broadcast_event_consumers
+             WHERE broadcast_event_consumers.namespace = broadcast_events.namespace),
+           18446744073709551615)
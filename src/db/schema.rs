@@ -1,10 +1,76 @@
 #![allow(proc_macro_derive_resolution_fallback)]
 
 table! {
-    broadcastsv1 (broadcaster_id, bchannel_id) {
+    broadcastsv1 (namespace, broadcaster_id, bchannel_id) {
+        namespace -> Varchar,
         broadcaster_id -> Varchar,
         bchannel_id -> Varchar,
+        created -> Timestamp,
         last_updated -> Timestamp,
         version -> Varchar,
+        sequence -> Unsigned<Bigint>,
+        expires_at -> Nullable<Datetime>,
+        comment -> Nullable<Varchar>,
+        metadata -> Nullable<Text>,
+        removed -> Bool,
+        last_version -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    pending_broadcastsv1 (id) {
+        id -> Unsigned<Bigint>,
+        namespace -> Varchar,
+        broadcaster_id -> Varchar,
+        bchannel_id -> Varchar,
+        version -> Varchar,
+        effective_at -> Datetime,
+        created -> Timestamp,
+        expires_at -> Nullable<Datetime>,
+        comment -> Nullable<Varchar>,
+        metadata -> Nullable<Text>,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Unsigned<Bigint>,
+        subscriber -> Varchar,
+        broadcaster_id -> Varchar,
+        bchannel_id -> Varchar,
+        version -> Varchar,
+        sequence -> Unsigned<Bigint>,
+        traceparent -> Nullable<Varchar>,
+        attempts -> Unsigned<Integer>,
+        next_attempt_at -> Datetime,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    broadcast_events (event_offset) {
+        event_offset -> Unsigned<Bigint>,
+        namespace -> Varchar,
+        broadcaster_id -> Varchar,
+        bchannel_id -> Varchar,
+        action -> Varchar,
+        version -> Nullable<Varchar>,
+        sequence -> Unsigned<Bigint>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    broadcast_event_offsets (id) {
+        id -> Unsigned<Tinyint>,
+        last_offset -> Unsigned<Bigint>,
+    }
+}
+
+table! {
+    broadcast_event_consumers (consumer, namespace) {
+        consumer -> Varchar,
+        namespace -> Varchar,
+        acked_offset -> Unsigned<Bigint>,
     }
 }
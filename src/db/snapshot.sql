@@ -0,0 +1,8 @@
+SELECT (SELECT last_offset
+          FROM broadcast_event_offsets
+         WHERE id = 1) AS last_offset,
+       (SELECT MIN(expires_at)
+          FROM broadcastsv1
+         WHERE namespace = ?
+           AND NOT removed
+           AND expires_at > ?) AS next_expiry
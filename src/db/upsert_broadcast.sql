@@ -1,3 +1,12 @@
-INSERT INTO broadcastsv1 (broadcaster_id, bchannel_id, version)
-VALUES (?, ?, ?)
-ON DUPLICATE KEY UPDATE version = ?;
+INSERT INTO broadcastsv1
+    (namespace, broadcaster_id, bchannel_id, version, last_version, sequence, expires_at, comment, metadata)
+VALUES (?, ?, ?, ?, NULLIF(?, ?), 1, ?, ?, ?)
+ON DUPLICATE KEY UPDATE
+    created = IF(removed, CURRENT_TIMESTAMP, created),
+    removed = FALSE,
+    version = ?,
+    last_version = COALESCE(VALUES(last_version), last_version),
+    sequence = sequence + 1,
+    expires_at = ?,
+    comment = ?,
+    metadata = ?;
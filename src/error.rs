@@ -18,6 +18,9 @@ use slog::{debug, warn};
 use thiserror::Error;
 
 use crate::logging::RequestLogger;
+use crate::metrics::ErrorMetrics;
+use crate::reporting::{self, ReportPolicy};
+use crate::request_id::RequestId;
 
 pub type HandlerResult<T> = result::Result<T, HandlerError>;
 
@@ -43,6 +46,16 @@ pub enum HandlerErrorKind {
     MissingVersionDataError,
     #[error("Invalid Version (must be ASCII, <= 200 characters)")]
     InvalidVersionDataError,
+    #[error("Version rejected by policy: {0}")]
+    VersionPolicyError(String),
+    #[error("Version {0:?} is older than the current version {1:?}")]
+    VersionRegressionError(String, String),
+    #[error("Invalid query parameter: {0}")]
+    InvalidParameter(String),
+    #[error("Invalid version body: {0}")]
+    InvalidVersionBody(String),
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(String),
 
     /// 401 "Unauthorized" (unauthenticated)
     #[error("Missing authorization header")]
@@ -58,6 +71,10 @@ pub enum HandlerErrorKind {
     #[error("Not Found")]
     NotFound,
 
+    /// 406 Not Acceptable
+    #[error("None of the acceptable response formats are supported")]
+    NotAcceptable,
+
     /// 500 Internal Server Errors
     #[error("Unexpected megaphone error: {0}")]
     InternalError(String),
@@ -93,6 +110,7 @@ impl HandlerErrorKind {
             HandlerErrorKind::MissingAuth | HandlerErrorKind::InvalidAuth => Status::Unauthorized,
             HandlerErrorKind::Unauthorized => Status::Forbidden,
             HandlerErrorKind::NotFound => Status::NotFound,
+            HandlerErrorKind::NotAcceptable => Status::NotAcceptable,
             HandlerErrorKind::InternalError(_) | HandlerErrorKind::IoError(_) => {
                 Status::InternalServerError
             }
@@ -111,11 +129,17 @@ impl HandlerErrorKind {
             HandlerErrorKind::InvalidBchannelId => 101,
             HandlerErrorKind::MissingVersionDataError => 102,
             HandlerErrorKind::InvalidVersionDataError => 103,
+            HandlerErrorKind::VersionPolicyError(_) => 104,
+            HandlerErrorKind::VersionRegressionError(..) => 105,
+            HandlerErrorKind::InvalidParameter(_) => 106,
+            HandlerErrorKind::InvalidVersionBody(_) => 107,
+            HandlerErrorKind::InvalidMetadata(_) => 108,
 
             HandlerErrorKind::MissingAuth => 120,
             HandlerErrorKind::InvalidAuth => 121,
             HandlerErrorKind::Unauthorized => 122,
             HandlerErrorKind::NotFound => 123,
+            HandlerErrorKind::NotAcceptable => 124,
 
             HandlerErrorKind::IoError(_) | HandlerErrorKind::InternalError(_) => 201,
 
@@ -180,12 +204,26 @@ impl<'r> Responder<'r> for HandlerError {
     fn respond_to(self, request: &Request<'_>) -> response::Result<'r> {
         let status = self.kind().http_status();
         let errno = self.kind().errno();
+        let request_id = RequestId::from_request(request);
         let log = RequestLogger::with_request(request).map_err(|_| Status::InternalServerError)?;
+        if let Some(error_metrics) = request.guard::<State<'_, ErrorMetrics>>().succeeded() {
+            error_metrics.record(errno, status);
+        }
         let sentry_client = request
             .guard::<State<'_, Option<sentry::ClientInitGuard>>>()
             .succeeded();
-        if sentry_client.is_some() {
-            sentry::capture_event(sentry::event_from_error(&self));
+        let reported = request
+            .guard::<State<'_, ReportPolicy>>()
+            .succeeded()
+            .map_or_else(
+                || ReportPolicy::default().reports(status, errno),
+                |policy| policy.reports(status, errno),
+            );
+        if sentry_client.is_some() && reported {
+            let mut event = sentry::event_from_error(&self);
+            event.tags.insert("errno".to_owned(), errno.to_string());
+            reporting::add_request_context(request, &mut event);
+            sentry::capture_event(event);
         };
         match status {
             Status::Unauthorized | Status::Forbidden => {
@@ -197,7 +235,8 @@ impl<'r> Responder<'r> for HandlerError {
         let json = json!({
             "code": status.code,
             "errno": errno,
-            "error": format!("{}", self)
+            "error": format!("{}", self),
+            "request_id": request_id.0,
         });
         let mut builder = Response::build_from(json.respond_to(request)?);
         if status == Status::Unauthorized {
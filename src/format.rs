@@ -0,0 +1,163 @@
+/// Negotiation of response body formats
+///
+/// Readers may request CBOR or MessagePack encodings of the same logical
+/// structure as the default JSON responses via the `Accept` header.
+use std::cmp::Ordering;
+use std::io::Cursor;
+
+use rocket::{
+    http::{Accept, ContentType, MediaType, Status},
+    outcome::IntoOutcome,
+    request::{self, FromRequest},
+    response::{self, Responder, Response},
+    Request,
+};
+use rocket_contrib::json::JsonValue;
+use serde_json::Value;
+use slog::error;
+
+use crate::error::{HandlerError, HandlerErrorKind, HandlerResult, VALIDATION_FAILED};
+use crate::logging::RequestLogger;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BodyFormat {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl BodyFormat {
+    fn from_media_type(media_type: &MediaType) -> Option<BodyFormat> {
+        let (top, sub) = (media_type.top(), media_type.sub());
+        if top == "*" && sub == "*" {
+            Some(BodyFormat::Json)
+        } else if top != "application" {
+            None
+        } else if sub == "json" || sub == "*" {
+            Some(BodyFormat::Json)
+        } else if sub == "cbor" {
+            Some(BodyFormat::Cbor)
+        } else if sub == "msgpack" || sub == "x-msgpack" {
+            Some(BodyFormat::MessagePack)
+        } else {
+            None
+        }
+    }
+
+    /// Choose the supported BodyFormat with the highest quality value from
+    /// the `Accept` header, defaulting to JSON when there's none
+    pub fn from_accept(accept: Option<&Accept>) -> HandlerResult<BodyFormat> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Ok(BodyFormat::Json),
+        };
+        let mut media_types: Vec<_> = accept
+            .iter()
+            .filter(|media_type| media_type.weight_or(1.0) > 0.0)
+            .collect();
+        // Stable: retains the header's order among equal weights
+        media_types.sort_by(|a, b| {
+            b.weight_or(1.0)
+                .partial_cmp(&a.weight_or(1.0))
+                .unwrap_or(Ordering::Equal)
+        });
+        media_types
+            .into_iter()
+            .find_map(|media_type| BodyFormat::from_media_type(media_type.media_type()))
+            .ok_or_else(|| HandlerErrorKind::NotAcceptable.into())
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            BodyFormat::Json => ContentType::JSON,
+            BodyFormat::Cbor => ContentType::new("application", "cbor"),
+            BodyFormat::MessagePack => ContentType::MsgPack,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for BodyFormat {
+    type Error = HandlerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, HandlerError> {
+        BodyFormat::from_accept(request.accept()).into_outcome(VALIDATION_FAILED)
+    }
+}
+
+/// A response body encoded in the negotiated BodyFormat
+#[derive(Debug)]
+pub struct Formatted {
+    format: BodyFormat,
+    value: Value,
+}
+
+impl Formatted {
+    pub fn new(format: BodyFormat, value: JsonValue) -> Formatted {
+        Formatted {
+            format,
+            value: value.into(),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Formatted {
+    fn respond_to(self, request: &Request<'_>) -> response::Result<'r> {
+        let body = match self.format {
+            BodyFormat::Json => return JsonValue(self.value).respond_to(request),
+            BodyFormat::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(&self.value, &mut body)
+                    .map(|_| body)
+                    .map_err(|e| e.to_string())
+            }
+            BodyFormat::MessagePack => {
+                rmp_serde::to_vec_named(&self.value).map_err(|e| e.to_string())
+            }
+        };
+        let body = body.map_err(|e| {
+            if let Ok(log) = RequestLogger::with_request(request) {
+                error!(log, "Could not encode response: {}", e);
+            }
+            Status::InternalServerError
+        })?;
+        Response::build()
+            .header(self.format.content_type())
+            .sized_body(Cursor::new(body))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use rocket::http::Accept;
+
+    use super::BodyFormat;
+
+    fn negotiate(accept: &str) -> Option<BodyFormat> {
+        BodyFormat::from_accept(Some(&Accept::from_str(accept).unwrap())).ok()
+    }
+
+    #[test]
+    fn test_from_accept() {
+        assert_eq!(BodyFormat::from_accept(None).ok(), Some(BodyFormat::Json));
+        assert_eq!(negotiate("*/*"), Some(BodyFormat::Json));
+        assert_eq!(negotiate("application/json"), Some(BodyFormat::Json));
+        assert_eq!(negotiate("application/cbor"), Some(BodyFormat::Cbor));
+        assert_eq!(
+            negotiate("application/msgpack"),
+            Some(BodyFormat::MessagePack)
+        );
+        assert_eq!(
+            negotiate("application/json;q=0.5, application/cbor"),
+            Some(BodyFormat::Cbor)
+        );
+        assert_eq!(
+            negotiate("text/html, application/x-msgpack;q=0.9, */*;q=0.1"),
+            Some(BodyFormat::MessagePack)
+        );
+        assert_eq!(negotiate("text/html"), None);
+        assert_eq!(negotiate("application/xml, application/cbor;q=0"), None);
+    }
+}
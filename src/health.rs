@@ -0,0 +1,570 @@
+/// Health checks of the `__heartbeat__` endpoint
+///
+/// HealthChecks runs a registry of HealthChecks, each reporting `ok`, `warn`
+/// or `fail` along with its timing:
+///
+/// * `pool`: saturation of the database pool (in use connections over its
+///   max size), warning at `heartbeat_pool_saturation_warn` (default 0.8)
+///   and failing at `heartbeat_pool_saturation_fail` (default 1.0)
+/// * `database`: latency of a `SELECT 1`, warning at
+///   `heartbeat_database_latency_warn` (milliseconds, default 250) and
+///   failing at `heartbeat_database_latency_fail` (default 2000)
+/// * `migrations`: whether the migrations applied at startup are still
+///   applied
+/// * `statsd`: failures to send metrics since the last heartbeat (a warning),
+///   when statsd is enabled
+/// * `sentry`: whether the Sentry transport drained its queue when last
+///   flushed (in the background), when Sentry is enabled
+///
+/// The heartbeat is unavailable (503) when a check reaches the status of
+/// the `heartbeat_unavailable_on` table (`warn`, `fail` or `never`), by
+/// default `fail` for `database` and `migrations` and `never` for `pool`
+/// (a saturated pool still serves requests, if slower), `statsd` and
+/// `sentry`, e.g.
+///
+/// ```toml
+/// [production.heartbeat_unavailable_on]
+/// database = "warn"
+/// pool = "fail"
+/// ```
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::RunQueryDsl;
+use diesel_migrations::MigrationConnection;
+use rocket::config::{ConfigError, Value};
+use rocket::Config;
+use serde::Serialize;
+
+use crate::db::MysqlPool;
+use crate::error::{HandlerError, HandlerResult};
+use crate::metrics::Metrics;
+
+const DEFAULT_POOL_SATURATION_WARN: f64 = 0.8;
+const DEFAULT_POOL_SATURATION_FAIL: f64 = 1.0;
+const DEFAULT_DATABASE_LATENCY_WARN: Duration = Duration::from_millis(250);
+const DEFAULT_DATABASE_LATENCY_FAIL: Duration = Duration::from_millis(2000);
+/// Names of the checks configurable in `heartbeat_unavailable_on`
+const CHECK_NAMES: [&str; 5] = ["pool", "database", "migrations", "statsd", "sentry"];
+/// How long the Sentry transport may take to drain its queue
+const SENTRY_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The outcome of a HealthCheck, ordered by severity
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The result of a HealthCheck
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Check {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Check {
+    pub fn ok(message: Option<String>) -> Check {
+        Check {
+            status: CheckStatus::Ok,
+            message,
+        }
+    }
+
+    pub fn warn(message: String) -> Check {
+        Check {
+            status: CheckStatus::Warn,
+            message: Some(message),
+        }
+    }
+
+    pub fn fail(message: String) -> Check {
+        Check {
+            status: CheckStatus::Fail,
+            message: Some(message),
+        }
+    }
+}
+
+/// A check of a dependency's health
+pub trait HealthCheck: Send + Sync {
+    /// Name of the check in the heartbeat
+    fn name(&self) -> &'static str;
+
+    fn check(&self) -> Check;
+
+    /// Status making the heartbeat unavailable by default (None for never)
+    fn unavailable_on(&self) -> Option<CheckStatus> {
+        Some(CheckStatus::Fail)
+    }
+}
+
+/// Return a check of a value against its warn and fail thresholds
+fn check_threshold<T: PartialOrd>(value: T, warn: T, fail: T, message: String) -> Check {
+    if value >= fail {
+        Check::fail(message)
+    } else if value >= warn {
+        Check::warn(message)
+    } else {
+        Check::ok(Some(message))
+    }
+}
+
+/// Read a non-negative number (integer or float) from the rocket Config
+fn get_threshold(config: &Config, name: &str, default: f64) -> HandlerResult<f64> {
+    let value = match config.get_extra(name) {
+        Ok(Value::Integer(value)) => Some(*value as f64),
+        Ok(Value::Float(value)) => Some(*value),
+        Err(ConfigError::Missing(_)) => Some(default),
+        _ => None,
+    };
+    value
+        .filter(|value| *value >= 0.0)
+        .ok_or_else(|| HandlerError::internal(format!("Invalid ROCKET_{}", name.to_uppercase())))
+}
+
+/// Read the `heartbeat_unavailable_on` table from the rocket Config
+fn unavailable_on_from_config(
+    config: &Config,
+) -> HandlerResult<HashMap<String, Option<CheckStatus>>> {
+    let mut unavailable_on = HashMap::new();
+    let table = match config.get_table("heartbeat_unavailable_on") {
+        Ok(table) => table,
+        Err(ConfigError::Missing(_)) => return Ok(unavailable_on),
+        Err(e) => Err(HandlerError::internal(format!(
+            "Invalid ROCKET_HEARTBEAT_UNAVAILABLE_ON: {}",
+            e
+        )))?,
+    };
+    for (name, value) in table {
+        if !CHECK_NAMES.contains(&name.as_str()) {
+            Err(HandlerError::internal(format!(
+                "Invalid heartbeat_unavailable_on check: {:?} (expected one of {:?})",
+                name, CHECK_NAMES
+            )))?
+        }
+        let status = match value.as_str() {
+            Some("warn") => Some(CheckStatus::Warn),
+            Some("fail") => Some(CheckStatus::Fail),
+            Some("never") => None,
+            _ => Err(HandlerError::internal(format!(
+                "Invalid heartbeat_unavailable_on value for: {:?} (expected warn, fail or never)",
+                name
+            )))?,
+        };
+        unavailable_on.insert(name.to_owned(), status);
+    }
+    Ok(unavailable_on)
+}
+
+/// Read a threshold in milliseconds from the rocket Config
+fn get_duration(config: &Config, name: &str, default: Duration) -> HandlerResult<Duration> {
+    let millis = get_threshold(config, name, default.as_millis() as f64)?;
+    Ok(Duration::from_secs_f64(millis / 1000.0))
+}
+
+/// Saturation of the database pool
+pub struct PoolCheck {
+    pool: MysqlPool,
+    warn: f64,
+    fail: f64,
+}
+
+impl HealthCheck for PoolCheck {
+    fn name(&self) -> &'static str {
+        "pool"
+    }
+
+    fn check(&self) -> Check {
+        let state = self.pool.state();
+        let in_use = state.connections - state.idle_connections;
+        let max_size = self.pool.max_size();
+        check_threshold(
+            f64::from(in_use) / f64::from(max_size),
+            self.warn,
+            self.fail,
+            format!("{} of {} connections in use", in_use, max_size),
+        )
+    }
+
+    fn unavailable_on(&self) -> Option<CheckStatus> {
+        None
+    }
+}
+
+/// Latency of a database query
+pub struct DatabaseCheck {
+    pool: MysqlPool,
+    warn: Duration,
+    fail: Duration,
+}
+
+impl HealthCheck for DatabaseCheck {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn check(&self) -> Check {
+        let start = Instant::now();
+        let result = self
+            .pool
+            .get_timeout(self.fail)
+            .map_err(|e| e.to_string())
+            .and_then(|conn| {
+                diesel::sql_query("SELECT 1")
+                    .execute(&*conn)
+                    .map_err(|e| e.to_string())
+            });
+        let elapsed = start.elapsed();
+        match result {
+            Ok(_) => check_threshold(
+                elapsed,
+                self.warn,
+                self.fail,
+                format!("{}ms latency", elapsed.as_millis()),
+            ),
+            Err(e) => Check::fail(e),
+        }
+    }
+}
+
+/// Whether the migrations applied at startup are still applied
+pub struct MigrationsCheck {
+    pool: MysqlPool,
+    applied: HashSet<String>,
+    timeout: Duration,
+}
+
+impl HealthCheck for MigrationsCheck {
+    fn name(&self) -> &'static str {
+        "migrations"
+    }
+
+    fn check(&self) -> Check {
+        let versions = match self
+            .pool
+            .get_timeout(self.timeout)
+            .map_err(|e| e.to_string())
+            .and_then(|conn| {
+                conn.previously_run_migration_versions()
+                    .map_err(|e| e.to_string())
+            }) {
+            Ok(versions) => versions,
+            Err(e) => return Check::fail(e),
+        };
+        let mut missing: Vec<_> = self.applied.difference(&versions).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            return Check::fail(format!("Missing migrations: {:?}", missing));
+        }
+        Check::ok(versions.iter().max().map(|latest| format!("At {}", latest)))
+    }
+}
+
+/// Failures to send metrics to statsd since the previous check
+pub struct StatsdCheck {
+    metrics: Metrics,
+    /// Counts of the previous check
+    previous: Mutex<(u64, u64)>,
+}
+
+impl HealthCheck for StatsdCheck {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn check(&self) -> Check {
+        let stats = match self.metrics.statsd_stats() {
+            Some(stats) => stats,
+            None => return Check::ok(Some("Disabled".to_owned())),
+        };
+        let (emitted, errors) = stats.counts();
+        let mut previous = self.previous.lock().unwrap_or_else(|e| e.into_inner());
+        let (new_emitted, new_errors) = (emitted - previous.0, errors - previous.1);
+        *previous = (emitted, errors);
+        if new_errors > 0 {
+            Check::warn(format!(
+                "{} of {} metrics failed to send: {}",
+                new_errors,
+                new_emitted + new_errors,
+                stats.last_error().unwrap_or_default()
+            ))
+        } else {
+            Check::ok(Some(format!("{} metrics sent", new_emitted)))
+        }
+    }
+
+    fn unavailable_on(&self) -> Option<CheckStatus> {
+        None
+    }
+}
+
+/// Whether the Sentry transport drains its queue
+///
+/// The transport is flushed in a background thread (so heartbeats never wait
+/// on it), each check reporting the outcome of the last completed flush.
+#[derive(Default)]
+pub struct SentryCheck {
+    /// Whether the last flush drained the queue (None before any completed)
+    drained: Arc<Mutex<Option<bool>>>,
+    flushing: Arc<AtomicBool>,
+}
+
+impl SentryCheck {
+    /// Flush the client's transport in the background, unless already
+    fn flush(&self, client: Arc<sentry::Client>) {
+        if self.flushing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let drained = self.drained.clone();
+        let flushing = self.flushing.clone();
+        let spawned = thread::Builder::new()
+            .name("sentry-check".to_owned())
+            .spawn(move || {
+                let result = client.flush(Some(SENTRY_FLUSH_TIMEOUT));
+                *drained.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+                flushing.store(false, Ordering::SeqCst);
+            });
+        if spawned.is_err() {
+            self.flushing.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl HealthCheck for SentryCheck {
+    fn name(&self) -> &'static str {
+        "sentry"
+    }
+
+    fn check(&self) -> Check {
+        match sentry::Hub::current().client() {
+            Some(client) if client.is_enabled() => self.flush(client),
+            _ => return Check::warn("Client disabled".to_owned()),
+        }
+        match *self.drained.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(true) => Check::ok(None),
+            Some(false) => Check::warn("Events pending in the transport".to_owned()),
+            None => Check::ok(Some("Not yet flushed".to_owned())),
+        }
+    }
+
+    fn unavailable_on(&self) -> Option<CheckStatus> {
+        None
+    }
+}
+
+/// The report of a HealthCheck
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckReport {
+    #[serde(flatten)]
+    pub check: Check,
+    /// Duration of the check in milliseconds
+    pub time_ms: f64,
+}
+
+/// The reports of the HealthChecks
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// The most severe status
+    pub status: CheckStatus,
+    /// Whether a check reached its unavailable status
+    #[serde(skip)]
+    pub unavailable: bool,
+    pub checks: BTreeMap<&'static str, CheckReport>,
+}
+
+/// The registry of HealthChecks
+pub struct HealthChecks {
+    checks: Vec<Box<dyn HealthCheck>>,
+    /// Configured unavailable statuses (None for never) per check name
+    unavailable_on: HashMap<String, Option<CheckStatus>>,
+}
+
+impl HealthChecks {
+    /// Return the HealthChecks of the dependencies
+    pub fn from_config(
+        config: &Config,
+        pool: MysqlPool,
+        applied_migrations: HashSet<String>,
+        metrics: Metrics,
+        sentry: bool,
+    ) -> HandlerResult<HealthChecks> {
+        let unavailable_on = unavailable_on_from_config(config)?;
+        let database_fail = get_duration(
+            config,
+            "heartbeat_database_latency_fail",
+            DEFAULT_DATABASE_LATENCY_FAIL,
+        )?;
+        let mut checks = HealthChecks {
+            checks: vec![],
+            unavailable_on,
+        };
+        checks.register(PoolCheck {
+            pool: pool.clone(),
+            warn: get_threshold(
+                config,
+                "heartbeat_pool_saturation_warn",
+                DEFAULT_POOL_SATURATION_WARN,
+            )?,
+            fail: get_threshold(
+                config,
+                "heartbeat_pool_saturation_fail",
+                DEFAULT_POOL_SATURATION_FAIL,
+            )?,
+        });
+        checks.register(DatabaseCheck {
+            pool: pool.clone(),
+            warn: get_duration(
+                config,
+                "heartbeat_database_latency_warn",
+                DEFAULT_DATABASE_LATENCY_WARN,
+            )?,
+            fail: database_fail,
+        });
+        checks.register(MigrationsCheck {
+            pool,
+            applied: applied_migrations,
+            timeout: database_fail,
+        });
+        if metrics.statsd_stats().is_some() {
+            checks.register(StatsdCheck {
+                metrics,
+                previous: Mutex::new((0, 0)),
+            });
+        }
+        if sentry {
+            checks.register(SentryCheck::default());
+        }
+        Ok(checks)
+    }
+
+    /// Add a HealthCheck to the registry
+    pub fn register<C: HealthCheck + 'static>(&mut self, check: C) {
+        self.checks.push(Box::new(check));
+    }
+
+    /// Run the HealthChecks
+    pub fn run(&self) -> HealthReport {
+        let mut report = HealthReport {
+            status: CheckStatus::Ok,
+            unavailable: false,
+            checks: BTreeMap::new(),
+        };
+        for check in &self.checks {
+            let start = Instant::now();
+            let result = check.check();
+            let time_ms = (start.elapsed().as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0;
+            let unavailable_on = self
+                .unavailable_on
+                .get(check.name())
+                .cloned()
+                .unwrap_or_else(|| check.unavailable_on());
+            report.status = report.status.max(result.status);
+            report.unavailable |= unavailable_on.map_or(false, |status| result.status >= status);
+            report.checks.insert(
+                check.name(),
+                CheckReport {
+                    check: result,
+                    time_ms,
+                },
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+
+    use rocket::config::{Config, Environment, Value};
+
+    use super::{Check, CheckStatus, HealthCheck, HealthChecks};
+
+    struct Fixed(&'static str, CheckStatus);
+
+    impl HealthCheck for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn check(&self) -> Check {
+            Check {
+                status: self.1,
+                message: None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let mut checks = HealthChecks {
+            checks: vec![],
+            unavailable_on: HashMap::new(),
+        };
+        checks.register(Fixed("a", CheckStatus::Ok));
+        checks.register(Fixed("b", CheckStatus::Warn));
+        let report = checks.run();
+        assert_eq!(report.status, CheckStatus::Warn);
+        assert!(!report.unavailable);
+        assert_eq!(report.checks["b"].check.status, CheckStatus::Warn);
+
+        checks
+            .unavailable_on
+            .insert("b".to_owned(), Some(CheckStatus::Warn));
+        assert!(checks.run().unavailable);
+
+        checks.register(Fixed("c", CheckStatus::Fail));
+        let report = checks.run();
+        assert_eq!(report.status, CheckStatus::Fail);
+        assert!(report.unavailable);
+
+        checks.unavailable_on.insert("b".to_owned(), None);
+        checks.unavailable_on.insert("c".to_owned(), None);
+        assert!(!checks.run().unavailable);
+    }
+
+    #[test]
+    fn test_threshold() {
+        assert_eq!(
+            super::check_threshold(0.5, 0.8, 1.0, "".to_owned()).status,
+            CheckStatus::Ok
+        );
+        assert_eq!(
+            super::check_threshold(0.8, 0.8, 1.0, "".to_owned()).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            super::check_threshold(1.0, 0.8, 1.0, "".to_owned()).status,
+            CheckStatus::Fail
+        );
+    }
+
+    #[test]
+    fn test_unavailable_on_config() {
+        let config = |entries: Vec<(&str, &str)>| {
+            let table: BTreeMap<String, Value> = entries
+                .into_iter()
+                .map(|(name, status)| (name.to_owned(), Value::from(status)))
+                .collect();
+            Config::build(Environment::Development)
+                .extra("heartbeat_unavailable_on", table)
+                .unwrap()
+        };
+        let unavailable_on = super::unavailable_on_from_config(&config(vec![
+            ("database", "warn"),
+            ("pool", "never"),
+        ]))
+        .unwrap();
+        assert_eq!(unavailable_on["database"], Some(CheckStatus::Warn));
+        assert_eq!(unavailable_on["pool"], None);
+        assert!(super::unavailable_on_from_config(&config(vec![("database", "bogus")])).is_err());
+        assert!(super::unavailable_on_from_config(&config(vec![("databse", "warn")])).is_err());
+    }
+}
@@ -1,37 +1,53 @@
 // Include for clippy error on `fn lbhearbeat` expansion
 #![allow(clippy::let_unit_value)]
 
+use std::collections::HashMap;
 use std::env;
 use std::io::Read;
 use std::time::Instant;
 
-use diesel::RunQueryDsl;
+use chrono::{DateTime, Duration, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use diesel::Connection;
 use lazy_static::lazy_static;
 use regex::Regex;
 use rocket::{
     self,
-    config::RocketConfig,
+    config::{ConfigError, RocketConfig},
     data::{self, FromDataSimple},
-    http::Status,
+    http::{ContentType, Status},
     outcome::IntoOutcome,
     request::{self, FromRequest},
     response::{content, status},
-    Data,
-    Outcome::{Failure, Success},
-    Request, Rocket,
+    Config, Data,
+    Outcome::Failure,
+    Request, Rocket, State,
 };
 use rocket_contrib::{json, json::JsonValue};
-use slog::{error, info};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use slog::{error, info, warn};
 
 use crate::auth;
+use crate::compression::Compression;
 use crate::db::{
     self,
-    models::{Broadcaster, Reader},
+    models::{Broadcast, BroadcastEvent, Broadcaster, NewVersion, Reader},
 };
 use crate::error::{HandlerError, HandlerErrorKind, HandlerResult, VALIDATION_FAILED};
+use crate::format::{BodyFormat, Formatted};
+use crate::health::{CheckStatus, HealthChecks};
 use crate::logging::{self, RequestLogger};
-use crate::metrics::Metrics;
+use crate::metrics::{ErrorMetrics, Metrics, RequestMetrics};
+use crate::namespace::{self, Namespace, NamespaceRouting};
+use crate::reporting::{self, ReportPolicy};
+use crate::request_id::RequestIds;
+use crate::shutdown::Shutdown;
+use crate::signing::{Signed, Signer};
+use crate::sweeper::Sweeper;
 use crate::tags::Tags;
+use crate::telemetry::{RequestTracing, Tracing};
+use crate::version_policy::VersionPolicies;
+use crate::webhooks::{WebhookDeliverer, Webhooks};
 
 lazy_static! {
     static ref URLSAFE_B64_RE: Regex = Regex::new(r"^[A-Za-z0-9\-_]+$").unwrap();
@@ -45,30 +61,133 @@ impl<'a, 'r> FromRequest<'a, 'r> for Broadcaster {
     }
 }
 
-/// A new broadcast
+/// Maximum size of a new broadcast's body
+const MAX_VERSION_BODY_SIZE: u64 = 64 * 1024;
+
+/// Default maximum size of a new broadcast's serialized metadata
+const DEFAULT_METADATA_MAX_SIZE: usize = 1024;
+
+/// Default number of broadcasts per page of the paginated dumps
+const DEFAULT_PAGE_LIMIT: u32 = 1000;
+
+/// Maximum number of broadcasts per page of the paginated dumps
+const MAX_PAGE_LIMIT: u32 = 10_000;
+
+/// Seconds an `effective_at` may be in the past (allowing for clock skew),
+/// taking effect immediately
+const EFFECTIVE_AT_MAX_SKEW: i64 = 60;
+
+/// Maximum size of a new broadcast's serialized metadata, from the rocket
+/// Config's `metadata_max_size`
 #[derive(Debug)]
+struct MetadataMaxSize(usize);
+
+impl MetadataMaxSize {
+    fn from_config(config: &Config) -> HandlerResult<MetadataMaxSize> {
+        match config.get_int("metadata_max_size") {
+            // Leave room in the body for the remaining fields
+            Ok(size) if size > 0 && size as u64 <= MAX_VERSION_BODY_SIZE / 2 => {
+                Ok(MetadataMaxSize(size as usize))
+            }
+            Err(ConfigError::Missing(_)) => Ok(MetadataMaxSize(DEFAULT_METADATA_MAX_SIZE)),
+            _ => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_METADATA_MAX_SIZE (must be between 1 and {})",
+                MAX_VERSION_BODY_SIZE / 2
+            ))),
+        }
+    }
+}
+
+/// A new broadcast
+///
+/// Read from either a plain text body of only the version, or a JSON body
+/// (with a JSON Content-Type) of the version along with its attributes.
+#[derive(Debug, Default)]
 struct VersionInput {
     value: String,
+    comment: Option<String>,
+    ttl: Option<u32>,
+    /// Serialized JSON object
+    metadata: Option<String>,
+}
+
+/// A new broadcast's JSON body
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VersionBody {
+    version: String,
+    comment: Option<String>,
+    ttl: Option<u32>,
+    metadata: Option<Map<String, Value>>,
+}
+
+impl VersionInput {
+    fn parse(body: String, is_json: bool, metadata_max_size: usize) -> HandlerResult<VersionInput> {
+        let input = if is_json {
+            let body: VersionBody = serde_json::from_str(&body)
+                .map_err(|e| HandlerErrorKind::InvalidVersionBody(e.to_string()))?;
+            if body
+                .comment
+                .as_ref()
+                .map_or(false, |c| c.chars().count() > 255)
+            {
+                Err(HandlerErrorKind::InvalidVersionBody(
+                    "comment must be <= 255 characters".to_owned(),
+                ))?
+            }
+            let metadata = body
+                .metadata
+                .map(|metadata| Value::Object(metadata).to_string());
+            if metadata
+                .as_ref()
+                .map_or(false, |metadata| metadata.len() > metadata_max_size)
+            {
+                Err(HandlerErrorKind::InvalidMetadata(format!(
+                    "must be <= {} bytes when serialized",
+                    metadata_max_size
+                )))?
+            }
+            VersionInput {
+                value: body.version,
+                comment: body.comment,
+                ttl: body.ttl,
+                metadata,
+            }
+        } else {
+            VersionInput {
+                value: body,
+                ..Default::default()
+            }
+        };
+        let value = &input.value;
+        if value.is_empty() || value.len() > 200 || !value.is_ascii() {
+            Err(HandlerErrorKind::InvalidVersionDataError)?
+        }
+        Ok(input)
+    }
 }
 
 impl FromDataSimple for VersionInput {
     type Error = HandlerError;
 
-    fn from_data(_: &Request<'_>, data: Data) -> data::Outcome<Self, HandlerError> {
-        let mut value = String::new();
-        if let Err(_e) = data.open().read_to_string(&mut value) {
+    fn from_data(request: &Request<'_>, data: Data) -> data::Outcome<Self, HandlerError> {
+        let mut body = String::new();
+        if let Err(_e) = data
+            .open()
+            .take(MAX_VERSION_BODY_SIZE)
+            .read_to_string(&mut body)
+        {
             return Failure((
                 VALIDATION_FAILED,
                 HandlerErrorKind::MissingVersionDataError.into(),
             ));
         };
-        if value.is_empty() || value.len() > 200 || !value.is_ascii() {
-            return Failure((
-                VALIDATION_FAILED,
-                HandlerErrorKind::InvalidVersionDataError.into(),
-            ));
-        }
-        Success(VersionInput { value })
+        let is_json = request.content_type().map_or(false, |ct| ct.is_json());
+        let metadata_max_size = request
+            .guard::<State<'_, MetadataMaxSize>>()
+            .succeeded()
+            .map_or(DEFAULT_METADATA_MAX_SIZE, |max_size| max_size.0);
+        VersionInput::parse(body, is_json, metadata_max_size).into_outcome(VALIDATION_FAILED)
     }
 }
 
@@ -82,16 +201,75 @@ impl<'a, 'r> FromRequest<'a, 'r> for Reader {
 
 // REST Functions
 
+/// Parse an RFC 3339 timestamp query parameter into a UTC NaiveDateTime
+fn parse_timestamp(name: &str, value: &str) -> HandlerResult<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.naive_utc())
+        .map_err(|_| {
+            HandlerErrorKind::InvalidParameter(format!("{} must be an RFC 3339 timestamp", name))
+                .into()
+        })
+}
+
+/// Format a UTC NaiveDateTime as an RFC 3339 timestamp
+fn format_timestamp(ts: &NaiveDateTime) -> String {
+    Utc.from_utc_datetime(ts)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Determine a new version's expiry from either its `ttl` or `expires_at`
+fn parse_expiry(
+    starts: NaiveDateTime,
+    ttl: Option<u32>,
+    expires_at: Option<String>,
+) -> HandlerResult<Option<NaiveDateTime>> {
+    let expires_at = match (ttl, expires_at) {
+        (None, None) => return Ok(None),
+        (Some(ttl), None) => {
+            if ttl == 0 {
+                Err(HandlerErrorKind::InvalidParameter(
+                    "ttl must be a positive integer".to_owned(),
+                ))?
+            }
+            starts + Duration::seconds(ttl.into())
+        }
+        (None, Some(expires_at)) => parse_timestamp("expires_at", &expires_at)?,
+        (Some(_), Some(_)) => Err(HandlerErrorKind::InvalidParameter(
+            "Only one of ttl or expires_at may be specified".to_owned(),
+        ))?,
+    };
+    if expires_at <= starts {
+        Err(HandlerErrorKind::InvalidParameter(
+            "expires_at must be later than when the version takes effect".to_owned(),
+        ))?
+    }
+    Ok(Some(expires_at))
+}
+
 #[allow(clippy::too_many_arguments)]
 /// Set a version for a broadcaster / bchannel
-#[put("/v1/broadcasts/<broadcaster_id>/<bchannel_id>", data = "<version>")]
+///
+/// A future `effective_at` (RFC 3339) timestamp schedules the version as a
+/// PendingBroadcast instead. Either a `ttl` (in seconds, from when the
+/// version takes effect) or an `expires_at` (RFC 3339) timestamp set when the
+/// version expires.
+#[put(
+    "/v1/broadcasts/<broadcaster_id>/<bchannel_id>?<effective_at>&<ttl>&<expires_at>",
+    data = "<version>"
+)]
 fn broadcast(
     conn: HandlerResult<db::Conn>,
     log: RequestLogger,
     broadcaster: HandlerResult<Broadcaster>,
     broadcaster_id: String,
     bchannel_id: String,
+    effective_at: Option<String>,
+    ttl: Option<String>,
+    expires_at: Option<String>,
     version: HandlerResult<VersionInput>,
+    policies: State<'_, VersionPolicies>,
+    webhooks: State<'_, Webhooks>,
+    namespace: Namespace,
     metrics: Metrics,
     base_tags: Tags,
 ) -> HandlerResult<status::Custom<JsonValue>> {
@@ -103,61 +281,452 @@ fn broadcast(
     if bchannel_id.len() > 128 || !URLSAFE_B64_RE.is_match(&bchannel_id) {
         Err(HandlerErrorKind::InvalidBchannelId)?
     }
+    let now = Utc::now().naive_utc();
+    let effective_at = effective_at
+        .map(|value| parse_timestamp("effective_at", &value))
+        .transpose()?;
+    if let Some(effective_at) = effective_at {
+        if effective_at < now - Duration::seconds(EFFECTIVE_AT_MAX_SKEW) {
+            Err(HandlerErrorKind::InvalidParameter(
+                "effective_at must not be in the past".to_owned(),
+            ))?
+        }
+    }
+    let effective_at = effective_at.filter(|effective_at| *effective_at > now);
+    let input = version?;
+    let ttl = match (ttl, input.ttl) {
+        (Some(_), Some(_)) => Err(HandlerErrorKind::InvalidParameter(
+            "ttl may only be specified once".to_owned(),
+        ))?,
+        (Some(ttl), None) => Some(ttl.parse::<u32>().map_err(|_| {
+            HandlerErrorKind::InvalidParameter("ttl must be a positive integer".to_owned())
+        })?),
+        (None, ttl) => ttl,
+    };
+    let expires_at = parse_expiry(effective_at.unwrap_or(now), ttl, expires_at)?;
 
     let mut tags = base_tags;
-    let version = version?.value;
+    let version = input.value;
+    let new_version = NewVersion {
+        version: version.clone(),
+        expires_at,
+        comment: input.comment,
+        metadata: input.metadata,
+    };
 
+    tags.tags.insert("namespace".to_owned(), namespace.0);
     tags.tags
         .insert("broadcaster".to_owned(), broadcaster_id.clone());
     tags.tags
         .insert("channel_id".to_owned(), bchannel_id.clone());
-    tags.tags.insert("version".to_owned(), version.clone());
+
+    if let Some(effective_at) = effective_at {
+        metrics.incr_with_tags("broadcast.cmd.schedule", Some(tags));
+        let broadcaster = broadcaster?;
+        let id = conn.transaction(|| {
+            broadcaster.check_version_policy(&conn, &policies, &bchannel_id, &version)?;
+            broadcaster.schedule_new_version(&conn, &bchannel_id, &new_version, effective_at)
+        })?;
+        let status = Status::Accepted;
+        let effective_at = format_timestamp(&effective_at);
+        info!(
+            log,
+            "Broadcast: {}/{} scheduled version: {} at: {}",
+            broadcaster_id,
+            bchannel_id,
+            &version,
+            &effective_at;
+            "code" => status.code,
+            "pending_id" => id
+        );
+        return Ok(status::Custom(
+            status,
+            json!({
+                "code": status.code,
+                "id": id,
+                "effective_at": effective_at
+            }),
+        ));
+    }
+
     metrics.incr_with_tags("broadcast.cmd.update", Some(tags.clone()));
 
     let start = Instant::now();
-    let created = broadcaster?.broadcast_new_version(&conn, &bchannel_id, &version)?;
+    let broadcaster = broadcaster?;
+    let update = conn.transaction(|| {
+        broadcaster.check_version_policy(&conn, &policies, &bchannel_id, &version)?;
+        broadcaster.broadcast_new_version(&conn, &bchannel_id, &new_version, &webhooks)
+    })?;
     metrics.timer_with_tags(
         "broadcast.update",
         (Instant::now() - start).as_millis() as u64,
         Some(tags),
     );
-    let status = if created { Status::Created } else { Status::Ok };
+    let status = if update.created {
+        Status::Created
+    } else {
+        Status::Ok
+    };
     info!(
         log,
         "Broadcast: {}/{} new version: {}",
         broadcaster_id,
         bchannel_id,
         &version;
-        "code" => status.code
+        "code" => status.code,
+        "sequence" => update.sequence
     );
     Ok(status::Custom(
         status,
         json!({
-            "code": status.code
+            "code": status.code,
+            "sequence": update.sequence
         }),
     ))
 }
 
+/// List a broadcaster's pending (scheduled) versions
+#[get("/v1/pending/<_broadcaster_id>")]
+fn get_pending(
+    conn: HandlerResult<db::Conn>,
+    broadcaster: HandlerResult<Broadcaster>,
+    _broadcaster_id: String,
+) -> HandlerResult<JsonValue> {
+    let conn = conn?;
+    let pending: Vec<Value> = broadcaster?
+        .pending_versions(&conn)?
+        .iter()
+        .map(|pending| {
+            serde_json::json!({
+                "id": pending.id,
+                "bchannel_id": pending.bchannel_id,
+                "version": pending.version,
+                "effective_at": format_timestamp(&pending.effective_at),
+                "expires_at": pending.expires_at.as_ref().map(format_timestamp),
+                "comment": pending.comment,
+                "metadata": parse_metadata(pending.metadata.as_deref()),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "code": 200,
+        "pending": pending
+    }))
+}
+
+/// Cancel a broadcaster's pending (scheduled) version
+#[delete("/v1/pending/<_broadcaster_id>/<id>")]
+fn cancel_pending(
+    conn: HandlerResult<db::Conn>,
+    log: RequestLogger,
+    broadcaster: HandlerResult<Broadcaster>,
+    _broadcaster_id: String,
+    id: u64,
+) -> HandlerResult<JsonValue> {
+    let conn = conn?;
+    let broadcaster = broadcaster?;
+    if !broadcaster.cancel_pending_version(&conn, id)? {
+        Err(HandlerErrorKind::NotFound)?
+    }
+    info!(
+        log,
+        "Cancelled pending broadcast: {} for: {}", id, broadcaster.id
+    );
+    Ok(json!({
+        "code": 200
+    }))
+}
+
+/// Deserialize a stored metadata JSON object
+fn parse_metadata(metadata: Option<&str>) -> Option<Value> {
+    metadata.and_then(|metadata| serde_json::from_str(metadata).ok())
+}
+
+/// Render a Broadcast for the extended dump format
+fn extended_broadcast(bcast: &Broadcast) -> Value {
+    serde_json::json!({
+        "version": bcast.version,
+        "sequence": bcast.sequence,
+        "expires_at": bcast.expires_at.as_ref().map(format_timestamp),
+        "comment": bcast.comment,
+        "metadata": parse_metadata(bcast.metadata.as_deref()),
+    })
+}
+
+/// Render Broadcasts for the v1 dump, keyed by their ids
+fn v1_broadcasts(rows: Vec<Broadcast>, extended: bool) -> JsonValue {
+    if extended {
+        json!(rows
+            .iter()
+            .map(|bcast| (bcast.id(), extended_broadcast(bcast)))
+            .collect::<HashMap<_, _>>())
+    } else {
+        json!(rows
+            .into_iter()
+            .map(|bcast| (bcast.id(), bcast.version))
+            .collect::<HashMap<_, _>>())
+    }
+}
+
 /// Dump the current version table
-#[get("/v1/broadcasts")]
+///
+/// `format=extended` renders each broadcast as an object including its
+/// sequence number instead of only its version.
+///
+/// Passing either `limit` or `after` (a broadcast id) dumps a page at a time
+/// instead, adding a `next` link and a `snapshot` marker (see
+/// `get_broadcasts_v2`).
+///
+/// Responses are signed when a signing key is configured.
+#[get("/v1/broadcasts?<format>&<limit>&<after>")]
 fn get_broadcasts(
     conn: HandlerResult<db::Conn>,
     reader: HandlerResult<Reader>,
+    format: Option<String>,
+    limit: Option<String>,
+    after: Option<String>,
+    body_format: HandlerResult<BodyFormat>,
     metrics: Metrics,
-) -> HandlerResult<JsonValue> {
+) -> HandlerResult<Signed<Formatted>> {
     metrics.incr("broadcast.cmd.dump");
     let conn = conn?;
+    let reader = reader?;
+    let body_format = body_format?;
+    let extended = match format.as_deref() {
+        None => false,
+        Some("extended") => true,
+        Some(_) => Err(HandlerErrorKind::InvalidParameter(
+            "format must be \"extended\"".to_owned(),
+        ))?,
+    };
     let start = Instant::now();
-    let broadcasts = reader?.read_broadcasts(&conn)?;
+    let result = if limit.is_none() && after.is_none() {
+        let broadcasts = if extended {
+            v1_broadcasts(reader.read_broadcast_rows(&conn)?, true)
+        } else {
+            json!(reader.read_broadcasts(&conn)?)
+        };
+        json!({
+            "code": 200,
+            "broadcasts": broadcasts
+        })
+    } else {
+        let limit = parse_limit(limit)?;
+        let after = after.as_deref().map(parse_after).transpose()?;
+        let page = reader.read_broadcast_page(&conn, after, limit)?;
+        let next = page.broadcasts.last().filter(|_| page.more).map(|last| {
+            let format = if extended { "&format=extended" } else { "" };
+            format!(
+                "{}?limit={}&after={}{}",
+                namespace::api_path("v1", &reader.namespace, "/broadcasts"),
+                limit,
+                last.id(),
+                format
+            )
+        });
+        json!({
+            "code": 200,
+            "snapshot": page.snapshot,
+            "broadcasts": v1_broadcasts(page.broadcasts, extended),
+            "next": next
+        })
+    };
     metrics.timer_with_tags(
         "broadcast.dump",
         (Instant::now() - start).as_millis() as u64,
         None,
     );
+    Ok(Signed(Formatted::new(body_format, result)))
+}
+
+/// Publish the public key verifying signed broadcast dumps
+#[get("/v1/signing_key")]
+fn signing_key(signer: State<'_, Option<Signer>>) -> HandlerResult<JsonValue> {
+    let signer = signer.inner().as_ref().ok_or(HandlerErrorKind::NotFound)?;
+    Ok(json!({
+        "code": 200,
+        "key_id": signer.key_id(),
+        "algorithm": "Ed25519",
+        "public_key": signer.public_key(),
+        "public_key_pem": signer.public_key_pem()?
+    }))
+}
+
+/// Read a single broadcast (signed like the dumps)
+#[get("/v1/broadcasts/<broadcaster_id>/<bchannel_id>")]
+fn get_broadcast(
+    conn: HandlerResult<db::Conn>,
+    reader: HandlerResult<Reader>,
+    broadcaster_id: String,
+    bchannel_id: String,
+    body_format: HandlerResult<BodyFormat>,
+    metrics: Metrics,
+) -> HandlerResult<Signed<Formatted>> {
+    metrics.incr("broadcast.cmd.read");
+    let conn = conn?;
+    let reader = reader?;
+    let body_format = body_format?;
+    let bcast = reader
+        .read_broadcast(&conn, &broadcaster_id, &bchannel_id)?
+        .ok_or(HandlerErrorKind::NotFound)?;
+    Ok(Signed(Formatted::new(
+        body_format,
+        json!({
+            "code": 200,
+            "id": bcast.id(),
+            "broadcast": extended_broadcast(&bcast)
+        }),
+    )))
+}
+
+/// Parse the `limit` query parameter of the paginated dumps
+fn parse_limit(limit: Option<String>) -> HandlerResult<u32> {
+    let limit = match limit {
+        None => return Ok(DEFAULT_PAGE_LIMIT),
+        Some(limit) => limit.parse::<u32>().ok(),
+    };
+    match limit {
+        Some(limit) if limit > 0 && limit <= MAX_PAGE_LIMIT => Ok(limit),
+        _ => Err(HandlerErrorKind::InvalidParameter(format!(
+            "limit must be an integer between 1 and {}",
+            MAX_PAGE_LIMIT
+        )))?,
+    }
+}
+
+/// Parse the `after` query parameter (a Broadcast id) of the paginated dumps
+fn parse_after(after: &str) -> HandlerResult<(&str, &str)> {
+    Ok(after.split_once('/').ok_or_else(|| {
+        HandlerErrorKind::InvalidParameter("after must be a broadcast id".to_owned())
+    })?)
+}
+
+/// Render a Broadcast for the v2 dump
+fn v2_broadcast(bcast: &Broadcast) -> Value {
+    serde_json::json!({
+        "id": bcast.id(),
+        "broadcaster_id": bcast.broadcaster_id,
+        "bchannel_id": bcast.bchannel_id,
+        "version": bcast.version,
+        "sequence": bcast.sequence,
+        "created": format_timestamp(&bcast.created),
+        "last_updated": format_timestamp(&bcast.last_updated),
+        "expires_at": bcast.expires_at.as_ref().map(format_timestamp),
+        "comment": bcast.comment,
+        "metadata": parse_metadata(bcast.metadata.as_deref()),
+    })
+}
+
+/// Dump a page of the current broadcasts as full objects, ordered by id
+/// (signed like the v1 dump)
+///
+/// `next` links to the following page (if any). `snapshot` changes whenever
+/// the broadcasts do, so differing values across pages indicate the
+/// broadcasts changed while paging.
+#[get("/v2/broadcasts?<limit>&<after>")]
+fn get_broadcasts_v2(
+    conn: HandlerResult<db::Conn>,
+    reader: HandlerResult<Reader>,
+    limit: Option<String>,
+    after: Option<String>,
+    body_format: HandlerResult<BodyFormat>,
+    metrics: Metrics,
+) -> HandlerResult<Signed<Formatted>> {
+    metrics.incr("broadcast.cmd.dump_v2");
+    let conn = conn?;
+    let reader = reader?;
+    let body_format = body_format?;
+    let limit = parse_limit(limit)?;
+    let after = after.as_deref().map(parse_after).transpose()?;
+    let start = Instant::now();
+    let page = reader.read_broadcast_page(&conn, after, limit)?;
+    metrics.timer_with_tags(
+        "broadcast.dump_v2",
+        (Instant::now() - start).as_millis() as u64,
+        None,
+    );
+
+    let next = page.broadcasts.last().filter(|_| page.more).map(|last| {
+        format!(
+            "{}?limit={}&after={}",
+            namespace::api_path("v2", &reader.namespace, "/broadcasts"),
+            limit,
+            last.id()
+        )
+    });
+    Ok(Signed(Formatted::new(
+        body_format,
+        json!({
+            "code": 200,
+            "snapshot": page.snapshot,
+            "broadcasts": page.broadcasts.iter().map(v2_broadcast).collect::<Vec<_>>(),
+            "next": next
+        }),
+    )))
+}
+
+/// Render a BroadcastEvent for the change log
+fn event(event: &BroadcastEvent) -> Value {
+    serde_json::json!({
+        "offset": event.offset,
+        "id": event.id(),
+        "broadcaster_id": event.broadcaster_id,
+        "bchannel_id": event.bchannel_id,
+        "action": event.action,
+        "version": event.version,
+        "sequence": event.sequence,
+        "created": format_timestamp(&event.created),
+    })
+}
+
+/// Read the change log of broadcasts, following the `after` offset
+/// (defaulting to the reader's acknowledged offset)
+#[get("/v1/events?<after>&<limit>")]
+fn get_events(
+    conn: HandlerResult<db::Conn>,
+    reader: HandlerResult<Reader>,
+    after: Option<String>,
+    limit: Option<String>,
+    body_format: HandlerResult<BodyFormat>,
+    metrics: Metrics,
+) -> HandlerResult<Formatted> {
+    metrics.incr("broadcast.cmd.events");
+    let conn = conn?;
+    let reader = reader?;
+    let body_format = body_format?;
+    let limit = parse_limit(limit)?;
+    let acked_offset = reader.acked_offset(&conn)?;
+    let after = match after {
+        Some(after) => after.parse::<u64>().map_err(|_| {
+            HandlerErrorKind::InvalidParameter("after must be an event offset".to_owned())
+        })?,
+        None => acked_offset,
+    };
+    let events = reader.read_events(&conn, after, limit)?;
+    Ok(Formatted::new(
+        body_format,
+        json!({
+            "code": 200,
+            "acked_offset": acked_offset,
+            "events": events.iter().map(event).collect::<Vec<_>>()
+        }),
+    ))
+}
 
+/// Acknowledge the change log up to (and including) an offset
+#[put("/v1/events/ack/<offset>")]
+fn ack_events(
+    conn: HandlerResult<db::Conn>,
+    reader: HandlerResult<Reader>,
+    offset: u64,
+    metrics: Metrics,
+) -> HandlerResult<JsonValue> {
+    metrics.incr("broadcast.cmd.ack_events");
+    let conn = conn?;
+    let reader = reader?;
     Ok(json!({
         "code": 200,
-        "broadcasts": broadcasts
+        "acked_offset": reader.ack_events(&conn, offset)?
     }))
 }
 
@@ -179,26 +748,41 @@ fn version() -> content::Json<&'static str> {
     content::Json(include_str!("../version.json"))
 }
 
+/// Report the health checks
+///
+/// The top level `status` and `database` remain `ok` or `error` (for any
+/// other status) as before the per-check statuses of `checks`.
 #[get("/__heartbeat__")]
-fn heartbeat(conn: HandlerResult<db::Conn>, log: RequestLogger) -> status::Custom<JsonValue> {
-    let result = conn.and_then(|conn| Ok(diesel::sql_query("SELECT 1").execute(&*conn)?));
+fn heartbeat(checks: State<'_, HealthChecks>, log: RequestLogger) -> status::Custom<JsonValue> {
+    let report = checks.run();
+    for (name, check) in &report.checks {
+        let message = check.check.message.as_deref().unwrap_or_default();
+        match check.check.status {
+            CheckStatus::Ok => (),
+            CheckStatus::Warn => warn!(log, "Heartbeat check {} degraded: {}", name, message),
+            CheckStatus::Fail => error!(log, "Heartbeat check {} failed: {}", name, message),
+        }
+    }
 
-    let status = match result {
-        Ok(_) => Status::Ok,
-        Err(e) => {
-            let status = Status::ServiceUnavailable;
-            error!(log, "Database heartbeat failed: {}", e; "code" => status.code);
-            status
+    let status = if report.unavailable {
+        Status::ServiceUnavailable
+    } else {
+        Status::Ok
+    };
+    let msg = |status: CheckStatus| {
+        if status == CheckStatus::Ok {
+            "ok"
+        } else {
+            "error"
         }
     };
-
-    let msg = if status == Status::Ok { "ok" } else { "error" };
     status::Custom(
         status,
         json!({
-            "status": msg,
+            "status": msg(report.status),
             "code": status.code,
-            "database": msg,
+            "database": report.checks.get("database").map(|check| msg(check.check.status)),
+            "checks": report.checks,
         }),
     )
 }
@@ -206,28 +790,25 @@ fn heartbeat(conn: HandlerResult<db::Conn>, log: RequestLogger) -> status::Custo
 #[get("/__lbheartbeat__")]
 fn lbheartbeat() {}
 
+/// Expose the metrics in the Prometheus text format (when enabled)
+#[get("/__metrics__")]
+fn prometheus_metrics(
+    pool: State<'_, db::MysqlPool>,
+    metrics: Metrics,
+) -> HandlerResult<content::Content<String>> {
+    let registry = metrics.prometheus().ok_or(HandlerErrorKind::NotFound)?;
+    db::record_pool_gauges(&pool, &metrics);
+    Ok(content::Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        registry.render(),
+    ))
+}
+
 #[catch(404)]
 fn not_found() -> HandlerResult<()> {
     Err(HandlerErrorKind::NotFound)?
 }
 
-pub fn get_sentry(config: &rocket::config::Config) -> Option<sentry::ClientInitGuard> {
-    let opts = sentry::ClientOptions {
-        // debug: true,
-        ..Default::default()
-    };
-    if let Ok(sentry_dsn) = config.get_string("sentry_dsn") {
-        Some(sentry::init((sentry_dsn, opts)))
-    } else {
-        // Check the global env to see if we need to connect to sentry.
-        if env::var("SENTRY_DSN").is_ok() {
-            Some(sentry::init(opts))
-        } else {
-            None
-        }
-    }
-}
-
 pub fn rocket() -> HandlerResult<Rocket> {
     // RocketConfig::init basically
     let rconfig = RocketConfig::read().unwrap_or_else(|_| {
@@ -245,44 +826,134 @@ pub fn rocket() -> HandlerResult<Rocket> {
 fn setup_rocket(rocket: Rocket) -> HandlerResult<Rocket> {
     let pool = db::pool_from_config(rocket.config())?;
     let authenticator = auth::BearerTokenAuthenticator::from_config(rocket.config())?;
+    let policies = VersionPolicies::from_config(rocket.config())?;
+    let metadata_max_size = MetadataMaxSize::from_config(rocket.config())?;
+    let signer = Signer::from_config(rocket.config())?;
+    let webhooks = Webhooks::from_config(rocket.config())?;
     let environment = rocket.config().environment;
-    let sentry_client = get_sentry(rocket.config());
+    let sentry_client = reporting::init_sentry(rocket.config())?;
+    let report_policy = ReportPolicy::from_config(rocket.config())?;
     let logger = logging::init_logging(rocket.config(), &sentry_client)?;
+    let tracing = Tracing::from_config(rocket.config())?;
+    if let Some(tracing) = &tracing {
+        tracing.install((*logger).clone());
+    }
     let tags = Tags::init(rocket.config())?;
     let metrics = Metrics::init(rocket.config(), &sentry_client)?;
+    let compression = Compression::from_config(rocket.config(), metrics.clone())?;
+    let request_metrics = RequestMetrics::new(metrics.clone());
+    let error_metrics = ErrorMetrics::from_config(rocket.config(), metrics.clone())?;
+    // Stops the background threads when dropped along with the Rocket
+    let shutdown = Shutdown::default();
+    error_metrics.spawn(&shutdown)?;
     info!(logger, "Starting up");
-    db::run_embedded_migrations(rocket.config())?;
+    let applied_migrations = db::run_embedded_migrations(rocket.config())?;
+    let health_checks = HealthChecks::from_config(
+        rocket.config(),
+        pool.clone(),
+        applied_migrations,
+        metrics.clone(),
+        sentry_client.is_some(),
+    )?;
+    if let Some(sweeper) = Sweeper::from_config(
+        rocket.config(),
+        pool.clone(),
+        (*logger).clone(),
+        metrics.clone(),
+        webhooks.clone(),
+    )? {
+        sweeper.spawn(&shutdown)?;
+    }
+    if let Some(monitor) =
+        db::PoolMonitor::from_config(rocket.config(), pool.clone(), metrics.clone())?
+    {
+        monitor.spawn(&shutdown)?;
+    }
+    if let Some(deliverer) = WebhookDeliverer::from_config(
+        rocket.config(),
+        webhooks.clone(),
+        pool.clone(),
+        (*logger).clone(),
+        metrics.clone(),
+    )? {
+        deliverer.spawn(&shutdown)?;
+    }
     Ok(rocket
         .manage(pool)
         .manage(authenticator)
+        .manage(policies)
+        .manage(metadata_max_size)
+        .manage(signer)
+        .manage(webhooks)
         .manage(environment)
         .manage(logger)
         .manage(metrics)
+        .manage(error_metrics)
         .manage(tags)
         .manage(sentry_client)
+        .manage(report_policy)
+        .manage(health_checks)
+        .manage(tracing)
+        .manage(shutdown)
         .mount(
             "/",
             routes![
                 broadcast,
+                get_pending,
+                cancel_pending,
                 get_broadcasts,
+                get_broadcast,
+                get_broadcasts_v2,
+                get_events,
+                ack_events,
+                signing_key,
                 version,
                 heartbeat,
                 lbheartbeat,
+                prometheus_metrics,
                 log_check
             ],
         )
-        .register(catchers![not_found]))
+        .register(catchers![not_found])
+        .attach(RequestIds)
+        .attach(request_metrics)
+        .attach(RequestTracing)
+        .attach(NamespaceRouting)
+        .attach(compression))
 }
 
 #[cfg(test)]
 mod test {
     use crate::auth::test::to_table;
+    use crate::db::{
+        models::{
+            Broadcast, BroadcastEvent, Broadcaster, Expiration, NewVersion, PendingBroadcast,
+            Promotion, Reader,
+        },
+        schema::webhook_deliveries,
+        MysqlPool,
+    };
+    use crate::logging::RequestLogger;
+    use crate::metrics::{ErrorMetrics, Metrics};
+    use crate::namespace::DEFAULT_NAMESPACE;
+    use crate::signing::test::{signing_key_file, verify};
+    use crate::telemetry::Tracing;
+    use crate::version_policy::VersionPolicies;
+    use crate::webhooks::{
+        test::{stand_in, subscriber},
+        WebhookDeliverer, Webhooks,
+    };
+    use chrono::{DateTime, Duration, SecondsFormat, Utc};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
     use rocket::config::{Config, Environment, RocketConfig, Value as RValue};
-    use rocket::http::{Header, Status};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::Client;
     use rocket::response::Response;
     use rocket_contrib::json;
     use serde_json::{self, Value};
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+    use std::io::Read;
 
     use super::setup_rocket;
 
@@ -291,6 +962,7 @@ mod test {
         Foo,
         FooAlt,
         Baz,
+        Counter,
         Reader,
     }
 
@@ -300,6 +972,7 @@ mod test {
                 Auth::Foo => "feedfacedeadbeef",
                 Auth::FooAlt => "deadbeeffacefeed",
                 Auth::Baz => "baada555deadbeef",
+                Auth::Counter => "c0c0c0c0deadbeef",
                 Auth::Reader => "00000000deadbeef",
             };
             Header::new("Authorization".to_string(), format!("Bearer {}", token))
@@ -311,6 +984,11 @@ mod test {
     /// The managed db pool is set to a maxiumum of one connection w/
     /// a transaction began that is never committed
     fn rocket_client() -> Client {
+        rocket_client_with(vec![])
+    }
+
+    /// Return a Rocket Client for testing with additional Config extras
+    fn rocket_client_with(extras: Vec<(&str, RValue)>) -> Client {
         // create a separate test config but inheriting database_url
         let rconfig = RocketConfig::read().expect("reading rocket Config failed");
         let database_url = rconfig
@@ -318,32 +996,49 @@ mod test {
             .get_str("database_url")
             .expect("ROCKET_DATABASE_URL undefined")
             .to_owned();
-        let config = Config::build(Environment::Development)
+        let mut builder = Config::build(Environment::Development)
             .extra("database_url", RValue::String(database_url))
             .extra("database_pool_max_size", 1)
             .extra("database_use_test_transactions", true)
             .extra("json_logging", false)
+            .extra("sweep_interval", 0)
+            .extra("metadata_max_size", 64)
+            .extra("compression_threshold", 64)
             .extra(
                 "broadcaster_auth",
                 to_table(
                     [
                         "foo=feedfacedeadbeef,deadbeeffacefeed",
                         "baz=baada555deadbeef",
+                        "counter=c0c0c0c0deadbeef",
                     ]
                     .to_vec(),
                 ),
             )
+            .extra("version_policies", version_policies())
             .extra(
                 "reader_auth",
                 to_table(["reader=00000000deadbeef"].to_vec()),
-            )
-            .unwrap();
+            );
+        for (name, value) in extras {
+            builder = builder.extra(name, value);
+        }
+        let config = builder.unwrap();
         dbg!(&config);
 
         let rocket = setup_rocket(rocket::custom(config)).expect("rocket failed");
         Client::new(rocket).expect("rocket launch failed")
     }
 
+    fn version_policies() -> BTreeMap<String, RValue> {
+        let mut policy = BTreeMap::new();
+        policy.insert("format".to_owned(), RValue::from("integer"));
+        policy.insert("monotonic".to_owned(), RValue::from(true));
+        let mut policies = BTreeMap::new();
+        policies.insert("counter".to_owned(), RValue::Table(policy));
+        policies
+    }
+
     fn json_body(response: &mut Response<'_>) -> Value {
         assert!(response.content_type().map_or(false, |ct| ct.is_json()));
         serde_json::from_str(&response.body_string().unwrap()).unwrap()
@@ -358,14 +1053,20 @@ mod test {
             .body("v0")
             .dispatch();
         assert_eq!(response.status(), Status::Created);
-        assert_eq!(json_body(&mut response), *json!({"code": 201}));
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 201, "sequence": 1})
+        );
         let mut response = client
             .put("/v1/broadcasts/foo/bar")
             .header(Auth::FooAlt)
             .body("v1")
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
-        assert_eq!(json_body(&mut response), *json!({"code": 200}));
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "sequence": 2})
+        );
     }
 
     #[test]
@@ -387,12 +1088,47 @@ mod test {
         let mut response = client
             .put("/v1/broadcasts/foo")
             .header(Auth::Foo)
+            .header(Header::new("X-Request-Id", "test-put-no-id"))
             .body("v1")
             .dispatch();
         assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.headers().get_one("X-Request-Id"),
+            Some("test-put-no-id")
+        );
         assert_eq!(
             json_body(&mut response),
-            *json!({"code": 404, "errno": 123, "error": "Not Found"})
+            *json!({
+                "code": 404,
+                "errno": 123,
+                "error": "Not Found",
+                "request_id": "test-put-no-id",
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_id() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/broadcasts").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let request_id = response
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_owned();
+        assert_eq!(request_id.len(), 32);
+        assert_eq!(json_body(&mut response)["request_id"], request_id);
+
+        // Invalid ids are replaced
+        let response = client
+            .get("/__lbheartbeat__")
+            .header(Header::new("X-Request-Id", "not valid"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("X-Request-Id").unwrap().len(),
+            32
         );
     }
 
@@ -462,66 +1198,1485 @@ mod test {
     }
 
     #[test]
-    fn test_get_no_auth() {
+    fn test_put_version_policy() {
         let client = rocket_client();
-        let mut response = client.get("/v1/broadcasts").dispatch();
-        assert_eq!(response.status(), Status::Unauthorized);
-        assert!(response
-            .headers()
-            .get_one("WWW-Authenticate")
-            .unwrap()
-            .starts_with("Bearer "));
+        let put = |version: &str| {
+            client
+                .put("/v1/broadcasts/counter/bar")
+                .header(Auth::Counter)
+                .body(version)
+                .dispatch()
+        };
+        assert_eq!(put("2").status(), Status::Created);
+        assert_eq!(put("3").status(), Status::Ok);
+        assert_eq!(put("3").status(), Status::Ok);
+
+        let mut response = put("1");
+        assert_eq!(response.status(), Status::BadRequest);
         let result = json_body(&mut response);
-        assert_eq!(result["code"], 401);
-    }
+        assert_eq!(result["errno"], 105);
+        assert!(result["error"].as_str().unwrap().contains("older"));
 
-    #[test]
-    fn test_get_bad_auth() {
-        let client = rocket_client();
-        let mut response = client.get("/v1/broadcasts").header(Auth::Foo).dispatch();
-        assert_eq!(response.status(), Status::Forbidden);
+        let mut response = put("v4");
+        assert_eq!(response.status(), Status::BadRequest);
         let result = json_body(&mut response);
-        assert_eq!(result["code"], 403);
+        assert_eq!(result["errno"], 104);
+        assert!(result["error"].as_str().unwrap().contains("integer"));
+
+        assert_eq!(put("____NOP____").status(), Status::Ok);
+
+        // Versions can't regress by way of the NOP version
+        assert_eq!(put("100").status(), Status::Ok);
+        assert_eq!(put("____NOP____").status(), Status::Ok);
+        let mut response = put("1");
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 105);
+        assert_eq!(put("100").status(), Status::Ok);
     }
 
     #[test]
-    fn test_put_get() {
+    fn test_schedule() {
         let client = rocket_client();
-        let _ = client
-            .put("/v1/broadcasts/foo/bar")
-            .header(Auth::FooAlt)
+        let effective_at =
+            (Utc::now() + Duration::hours(1)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut response = client
+            .put(format!(
+                "/v1/broadcasts/foo/bar?effective_at={}",
+                effective_at
+            ))
+            .header(Auth::Foo)
             .body("v1")
             .dispatch();
-        let _ = client
-            .put("/v1/broadcasts/baz/quux")
-            .header(Auth::Baz)
-            .body("v0")
-            .dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 202);
+        assert_eq!(result["effective_at"], effective_at);
+        let id = result["id"].as_u64().unwrap();
+
         let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {}})
+        );
+
+        let mut response = client.get("/v1/pending/foo").header(Auth::Foo).dispatch();
         assert_eq!(response.status(), Status::Ok);
         assert_eq!(
             json_body(&mut response),
-            *json!({"code": 200, "broadcasts": {"baz/quux": "v0", "foo/bar": "v1"}})
+            *json!({"code": 200, "pending": [{
+                "id": id,
+                "bchannel_id": "bar",
+                "version": "v1",
+                "effective_at": effective_at,
+                "expires_at": null,
+                "comment": null,
+                "metadata": null,
+            }]})
         );
-    }
 
-    #[test]
-    fn test_version() {
-        let client = rocket_client();
-        let mut response = client.get("/__version__").dispatch();
+        let response = client.get("/v1/pending/foo").header(Auth::Baz).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .delete(format!("/v1/pending/baz/{}", id))
+            .header(Auth::Baz)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .delete(format!("/v1/pending/foo/{}", id))
+            .header(Auth::Foo)
+            .dispatch();
         assert_eq!(response.status(), Status::Ok);
-        let result = json_body(&mut response);
-        assert_eq!(result["version"], "devel");
+        let mut response = client.get("/v1/pending/foo").header(Auth::Foo).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "pending": []})
+        );
     }
 
     #[test]
-    fn test_heartbeat() {
+    fn test_schedule_bad_effective_at() {
         let client = rocket_client();
-        let mut response = client.get("/__heartbeat__").dispatch();
-        assert_eq!(response.status(), Status::Ok);
-        let result = json_body(&mut response);
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar?effective_at=tomorrow")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+
+        let past =
+            |offset: Duration| (Utc::now() - offset).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut response = client
+            .put(format!(
+                "/v1/broadcasts/foo/bar?effective_at={}",
+                past(Duration::hours(1))
+            ))
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+
+        // Within the allowed clock skew it takes effect immediately
+        let response = client
+            .put(format!(
+                "/v1/broadcasts/foo/bar?effective_at={}",
+                past(Duration::seconds(5))
+            ))
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+    }
+
+    #[test]
+    fn test_promote_due() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/counter/bar")
+            .header(Auth::Counter)
+            .body("5")
+            .dispatch();
+        {
+            let pool = client.rocket().state::<MysqlPool>().unwrap();
+            let policies = client.rocket().state::<VersionPolicies>().unwrap();
+            let conn = pool.get().unwrap();
+            let broadcaster = Broadcaster::new(DEFAULT_NAMESPACE.to_owned(), "foo".to_owned());
+            let now = Utc::now().naive_utc();
+            let v1 = NewVersion {
+                version: "v1".to_owned(),
+                ..Default::default()
+            };
+            broadcaster
+                .schedule_new_version(&conn, "bar", &v1, now - Duration::seconds(1))
+                .unwrap();
+            let v2 = NewVersion {
+                version: "v2".to_owned(),
+                ..Default::default()
+            };
+            broadcaster
+                .schedule_new_version(&conn, "baz", &v2, now + Duration::hours(1))
+                .unwrap();
+            // Regressed since it was scheduled
+            let counter = Broadcaster::new(DEFAULT_NAMESPACE.to_owned(), "counter".to_owned());
+            let v3 = NewVersion {
+                version: "3".to_owned(),
+                ..Default::default()
+            };
+            counter
+                .schedule_new_version(&conn, "bar", &v3, now - Duration::seconds(1))
+                .unwrap();
+            let mut promoted =
+                PendingBroadcast::promote_due(&conn, policies, &Webhooks::default()).unwrap();
+            promoted.sort_by_key(|(pending, _)| pending.id());
+            assert_eq!(promoted.len(), 2);
+            assert_eq!(promoted[0].0.id(), "counter/bar");
+            match &promoted[0].1 {
+                Promotion::Rejected(e) => assert_eq!(e.kind().errno(), 105),
+                promotion => panic!("Unexpected promotion: {:?}", promotion),
+            }
+            assert_eq!(promoted[1].0.id(), "foo/bar");
+            assert!(matches!(promoted[1].1, Promotion::Promoted));
+            assert_eq!(broadcaster.pending_versions(&conn).unwrap().len(), 1);
+            assert!(counter.pending_versions(&conn).unwrap().is_empty());
+        }
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"counter/bar": "5", "foo/bar": "v1"}})
+        );
+    }
+
+    #[test]
+    fn test_put_json() {
+        let client = rocket_client();
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(ContentType::JSON)
+            .body(
+                json!({
+                    "version": "v1",
+                    "comment": "Roll out v1",
+                    "ttl": 3600,
+                    "metadata": {"collection": "main/cfr", "rollout": 50}
+                })
+                .to_string(),
+            )
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 201, "sequence": 1})
+        );
+
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"foo/bar": "v1"}})
+        );
+        let mut response = client
+            .get("/v1/broadcasts?format=extended")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let bcast = &result["broadcasts"]["foo/bar"];
+        assert_eq!(bcast["version"], "v1");
+        assert_eq!(bcast["comment"], "Roll out v1");
+        assert_eq!(
+            bcast["metadata"],
+            *json!({"collection": "main/cfr", "rollout": 50})
+        );
+        assert!(bcast["expires_at"].is_string());
+    }
+
+    #[test]
+    fn test_put_bad_json() {
+        let client = rocket_client();
+        for body in [
+            json!({"comment": "no version"}),
+            json!({"version": "v1", "bogus": true}),
+            json!({"version": "v1", "metadata": ["not", "an", "object"]}),
+            json!({"version": "v1", "comment": "x".repeat(256)}),
+        ] {
+            let mut response = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .header(ContentType::JSON)
+                .body(body.to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["errno"], 107);
+        }
+
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(ContentType::JSON)
+            .body(json!({"version": "v1", "metadata": {"hash": "0".repeat(64)}}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let result = json_body(&mut response);
+        assert_eq!(result["errno"], 108);
+        assert!(result["error"].as_str().unwrap().contains("64 bytes"));
+
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar?ttl=60")
+            .header(Auth::Foo)
+            .header(ContentType::JSON)
+            .body(json!({"version": "v1", "ttl": 60}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+    }
+
+    #[test]
+    fn test_put_ttl() {
+        let client = rocket_client();
+        let before = Utc::now().naive_utc();
+        let response = client
+            .put("/v1/broadcasts/foo/bar?ttl=3600")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let mut response = client
+            .get("/v1/broadcasts?format=extended")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let expires_at = DateTime::parse_from_rfc3339(
+            result["broadcasts"]["foo/bar"]["expires_at"]
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap()
+        .naive_utc();
+        assert!(expires_at >= before + Duration::seconds(3599));
+        assert!(expires_at <= Utc::now().naive_utc() + Duration::seconds(3600));
+
+        // a new version without an expiry clears it
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v2")
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts?format=extended")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert!(result["broadcasts"]["foo/bar"]["expires_at"].is_null());
+    }
+
+    #[test]
+    fn test_put_bad_expiry() {
+        let client = rocket_client();
+        for query in [
+            "ttl=0",
+            "ttl=soon",
+            "expires_at=2001-01-01T00:00:00Z",
+            "ttl=60&expires_at=2101-01-01T00:00:00Z",
+        ] {
+            let mut response = client
+                .put(format!("/v1/broadcasts/foo/bar?{}", query))
+                .header(Auth::Foo)
+                .body("v1")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(json_body(&mut response)["errno"], 106);
+        }
+    }
+
+    #[test]
+    fn test_expire_due() {
+        let client = rocket_client();
+        {
+            let pool = client.rocket().state::<MysqlPool>().unwrap();
+            let conn = pool.get().unwrap();
+            let expires_at = Some(Utc::now().naive_utc() - Duration::seconds(1));
+            for (broadcaster_id, bchannel_id) in [("foo", "bar"), ("baz", "quux")] {
+                let new_version = NewVersion {
+                    version: "v1".to_owned(),
+                    expires_at,
+                    ..Default::default()
+                };
+                Broadcaster::new(DEFAULT_NAMESPACE.to_owned(), broadcaster_id.to_owned())
+                    .broadcast_new_version(&conn, bchannel_id, &new_version, &Webhooks::default())
+                    .unwrap();
+            }
+            let new_version = NewVersion {
+                version: "v2".to_owned(),
+                expires_at: Some(Utc::now().naive_utc() + Duration::hours(1)),
+                ..Default::default()
+            };
+            Broadcaster::new(DEFAULT_NAMESPACE.to_owned(), "foo".to_owned())
+                .broadcast_new_version(&conn, "baz", &new_version, &Webhooks::default())
+                .unwrap();
+        }
+        // Expired broadcasts are hidden before they're swept
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"foo/baz": "v2"}})
+        );
+        let response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        {
+            let pool = client.rocket().state::<MysqlPool>().unwrap();
+            let conn = pool.get().unwrap();
+            let mut fallbacks = HashMap::new();
+            fallbacks.insert("baz/quux".to_owned(), "v0".to_owned());
+            let mut expired: Vec<(String, Expiration)> =
+                Broadcast::expire_due(&conn, &fallbacks, &Webhooks::default())
+                    .unwrap()
+                    .into_iter()
+                    .map(|(bcast, expiration)| (bcast.id(), expiration))
+                    .collect();
+            expired.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                expired,
+                vec![
+                    ("baz/quux".to_owned(), Expiration::Reverted("v0".to_owned())),
+                    ("foo/bar".to_owned(), Expiration::Removed),
+                ]
+            );
+        }
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"baz/quux": "v0", "foo/baz": "v2"}})
+        );
+
+        // A removed broadcast's sequence continues when it's broadcast again
+        let mut response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v3")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 201, "sequence": 2})
+        );
+    }
+
+    #[test]
+    fn test_webhooks() {
+        let (url, received) = stand_in(vec![200, 500]);
+        let mut webhooks = BTreeMap::new();
+        webhooks.insert(
+            "standin".to_owned(),
+            subscriber(&url, "s3cr3t", Some(vec!["foo/*"])),
+        );
+        // Nothing listens on the discard port
+        webhooks.insert(
+            "down".to_owned(),
+            subscriber("http://127.0.0.1:9/hook", "s3cr3t", Some(vec!["baz/quux"])),
+        );
+        let client = rocket_client_with(vec![
+            ("webhooks", RValue::Table(webhooks)),
+            // Never delivers in the background
+            ("webhook_interval", RValue::from(3600)),
+        ]);
+        let deliverer = WebhookDeliverer::from_config(
+            client.rocket().config(),
+            client.rocket().state::<Webhooks>().unwrap().clone(),
+            client.rocket().state::<MysqlPool>().unwrap().clone(),
+            (**client.rocket().state::<RequestLogger>().unwrap()).clone(),
+            client.rocket().state::<Metrics>().unwrap().clone(),
+        )
+        .unwrap()
+        .unwrap();
+
+        for (auth, id) in [(Auth::FooAlt, "foo/bar"), (Auth::Baz, "baz/quux")] {
+            let _ = client
+                .put(format!("/v1/broadcasts/{}", id))
+                .header(auth)
+                .body("v1")
+                .dispatch();
+        }
+        assert_eq!(deliverer.deliver_due().unwrap(), 2);
+        let request = received.recv().unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&request.body).unwrap(),
+            *json!({
+                "id": "foo/bar",
+                "broadcaster_id": "foo",
+                "bchannel_id": "bar",
+                "version": "v1",
+                "sequence": 1,
+            })
+        );
+        let webhooks = client.rocket().state::<Webhooks>().unwrap();
+        assert_eq!(
+            request.header("Megaphone-Webhook-Signature"),
+            Some(
+                webhooks
+                    .get("standin")
+                    .unwrap()
+                    .sign(request.body.as_bytes())
+                    .unwrap()
+                    .as_str()
+            )
+        );
+
+        // Failures are retried later
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v2")
+            .dispatch();
+        assert_eq!(deliverer.deliver_due().unwrap(), 1);
+        let request = received.recv().unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&request.body).unwrap()["version"],
+            "v2"
+        );
+        assert_eq!(deliverer.deliver_due().unwrap(), 0);
+        let pool = client.rocket().state::<MysqlPool>().unwrap();
+        let conn = pool.get().unwrap();
+        let mut attempts: Vec<(String, u32)> = webhook_deliveries::table
+            .select((webhook_deliveries::subscriber, webhook_deliveries::attempts))
+            .filter(webhook_deliveries::next_attempt_at.gt(Utc::now().naive_utc()))
+            .load(&*conn)
+            .unwrap();
+        attempts.sort();
+        assert_eq!(
+            attempts,
+            vec![("down".to_owned(), 1), ("standin".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_get_no_auth() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/broadcasts").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response
+            .headers()
+            .get_one("WWW-Authenticate")
+            .unwrap()
+            .starts_with("Bearer "));
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 401);
+    }
+
+    #[test]
+    fn test_get_bad_auth() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/broadcasts").header(Auth::Foo).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 403);
+    }
+
+    #[test]
+    fn test_put_get() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v1")
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"baz/quux": "v0", "foo/bar": "v1"}})
+        );
+    }
+
+    #[test]
+    fn test_put_get_extended() {
+        let client = rocket_client();
+        for version in ["v1", "v2"] {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::FooAlt)
+                .body(version)
+                .dispatch();
+        }
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts?format=extended")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {
+                "baz/quux": {
+                    "version": "v0",
+                    "sequence": 1,
+                    "expires_at": null,
+                    "comment": null,
+                    "metadata": null,
+                },
+                "foo/bar": {
+                    "version": "v2",
+                    "sequence": 2,
+                    "expires_at": null,
+                    "comment": null,
+                    "metadata": null,
+                },
+            }})
+        );
+
+        let mut response = client
+            .get("/v1/broadcasts?format=bogus")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+    }
+
+    #[test]
+    fn test_get_compressed() {
+        let client = rocket_client();
+        for bchannel_id in ["bar", "baz", "quux"] {
+            let _ = client
+                .put(format!("/v1/broadcasts/foo/{}", bchannel_id))
+                .header(Auth::FooAlt)
+                .body("v1")
+                .dispatch();
+        }
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        let expected = json_body(&mut response);
+        assert!(response.headers().get_one("Content-Encoding").is_none());
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+
+        for encoding in ["gzip", "br"] {
+            let mut response = client
+                .get("/v1/broadcasts")
+                .header(Auth::Reader)
+                .header(Header::new("Accept-Encoding", encoding))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(
+                response.headers().get_one("Content-Encoding"),
+                Some(encoding)
+            );
+            let body = response.body_bytes().unwrap();
+            let mut decoded = String::new();
+            if encoding == "gzip" {
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+            } else {
+                brotli::Decompressor::new(&body[..], 4096)
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+            }
+            assert_eq!(serde_json::from_str::<Value>(&decoded).unwrap(), expected);
+        }
+
+        // Below the threshold
+        let client = rocket_client_with(vec![("compression_threshold", 4096.into())]);
+        let response = client
+            .get("/__heartbeat__")
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch();
+        assert!(response.headers().get_one("Content-Encoding").is_none());
+    }
+
+    #[test]
+    fn test_get_formats() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v1")
+            .dispatch();
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        let expected = json_body(&mut response);
+
+        let mut response = client
+            .get("/v1/broadcasts")
+            .header(Auth::Reader)
+            .header(Header::new("Accept", "application/cbor"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "cbor"))
+        );
+        let body = response.body_bytes().unwrap();
+        let decoded: Value = ciborium::de::from_reader(&body[..]).unwrap();
+        assert_eq!(decoded, expected);
+
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .header(Header::new("Accept", "application/msgpack"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::MsgPack));
+        let body = response.body_bytes().unwrap();
+        let decoded: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(decoded["id"], "foo/bar");
+        assert_eq!(decoded["broadcast"]["version"], "v1");
+        assert_eq!(decoded["broadcast"]["sequence"], 1);
+
+        let mut response = client
+            .get("/v1/broadcasts")
+            .header(Auth::Reader)
+            .header(Header::new("Accept", "text/html"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotAcceptable);
+        assert_eq!(json_body(&mut response)["errno"], 124);
+    }
+
+    #[test]
+    fn test_get_single() {
+        let client = rocket_client();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v1")
+            .dispatch();
+        let mut response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "id": "foo/bar", "broadcast": {
+                "version": "v1",
+                "sequence": 1,
+                "expires_at": null,
+                "comment": null,
+                "metadata": null,
+            }})
+        );
+        let response = client
+            .get("/v1/broadcasts/foo/baz")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .get("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_get_signed() {
+        let path = signing_key_file("test_get_signed");
+        let client = rocket_client_with(vec![(
+            "signing_key_path",
+            RValue::from(path.to_str().unwrap()),
+        )]);
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v1")
+            .dispatch();
+        let mut response = client.get("/v1/signing_key").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let key = json_body(&mut response);
+        assert_eq!(key["algorithm"], "Ed25519");
+        let public_key = key["public_key"].as_str().unwrap();
+
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let signature = response
+            .headers()
+            .get_one("Megaphone-Signature")
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            response.headers().get_one("Megaphone-Key-Id"),
+            key["key_id"].as_str()
+        );
+        let body = response.body_bytes().unwrap();
+        assert!(verify(public_key, &body, &signature));
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            *json!({"code": 200, "broadcasts": {"foo/bar": "v1"}})
+        );
+
+        // As are the v2 dump and single broadcasts
+        for url in ["/v2/broadcasts", "/v1/broadcasts/foo/bar"] {
+            let mut response = client.get(url).header(Auth::Reader).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let signature = response
+                .headers()
+                .get_one("Megaphone-Signature")
+                .unwrap()
+                .to_owned();
+            let body = response.body_bytes().unwrap();
+            assert!(verify(public_key, &body, &signature));
+        }
+    }
+
+    #[test]
+    fn test_unsigned() {
+        let client = rocket_client();
+        let response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Megaphone-Signature").is_none());
+        let response = client.get("/v1/signing_key").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_get_paging() {
+        let client = rocket_client();
+        for (auth, id, version) in [
+            (Auth::Baz, "baz/quux", "v0"),
+            (Auth::FooAlt, "foo/bar", "v1"),
+            (Auth::FooAlt, "foo/baz", "v2"),
+        ] {
+            let _ = client
+                .put(format!("/v1/broadcasts/{}", id))
+                .header(auth)
+                .body(version)
+                .dispatch();
+        }
+        let mut response = client
+            .get("/v1/broadcasts?limit=2")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let first = json_body(&mut response);
+        assert_eq!(
+            first["broadcasts"],
+            *json!({"baz/quux": "v0", "foo/bar": "v1"})
+        );
+        assert_eq!(first["next"], "/v1/broadcasts?limit=2&after=foo/bar");
+
+        let mut response = client
+            .get(first["next"].as_str().unwrap())
+            .header(Auth::Reader)
+            .dispatch();
+        let second = json_body(&mut response);
+        assert_eq!(second["broadcasts"], *json!({"foo/baz": "v2"}));
+        assert!(second["next"].is_null());
+        assert_eq!(first["snapshot"], second["snapshot"]);
+
+        let mut response = client
+            .get("/v1/broadcasts?format=extended&limit=1&after=baz/quux")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"]["foo/bar"]["version"], "v1");
+        assert_eq!(
+            result["next"],
+            "/v1/broadcasts?limit=1&after=foo/bar&format=extended"
+        );
+
+        let response = client
+            .get("/v1/broadcasts?limit=abc")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn test_get_v2() {
+        let client = rocket_client();
+        let before = Utc::now().timestamp();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .header(ContentType::JSON)
+            .body(r#"{"version": "v1", "comment": "hi", "metadata": {"a": 1}}"#)
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v0")
+            .dispatch();
+        let mut response = client.get("/v2/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 200);
+        assert!(result["snapshot"].is_string());
+        assert!(result["next"].is_null());
+        let broadcasts = result["broadcasts"].as_array().unwrap();
+        let ids: Vec<_> = broadcasts.iter().map(|b| b["id"].clone()).collect();
+        assert_eq!(ids, vec!["baz/quux", "foo/bar"]);
+        let bcast = &broadcasts[1];
+        assert_eq!(bcast["broadcaster_id"], "foo");
+        assert_eq!(bcast["bchannel_id"], "bar");
+        assert_eq!(bcast["version"], "v1");
+        assert_eq!(bcast["sequence"], 1);
+        assert_eq!(bcast["comment"], "hi");
+        assert_eq!(bcast["metadata"], *json!({"a": 1}));
+        assert!(bcast["expires_at"].is_null());
+        for field in &["created", "last_updated"] {
+            let ts = DateTime::parse_from_rfc3339(bcast[field].as_str().unwrap()).unwrap();
+            assert!(ts.timestamp() >= before - 1);
+        }
+
+        let mut response = client
+            .get("/v2/broadcasts?limit=0")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+        let response = client.get("/v2/broadcasts").header(Auth::Foo).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_get_v2_paging() {
+        let client = rocket_client();
+        for (auth, id) in [
+            (Auth::Baz, "baz/quux"),
+            (Auth::FooAlt, "foo/bar"),
+            (Auth::FooAlt, "foo/baz"),
+        ] {
+            let _ = client
+                .put(format!("/v1/broadcasts/{}", id))
+                .header(auth)
+                .body("v1")
+                .dispatch();
+        }
+
+        let mut ids = vec![];
+        let mut snapshots = vec![];
+        let mut next = "/v2/broadcasts?limit=2".to_owned();
+        loop {
+            let mut response = client.get(next).header(Auth::Reader).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let result = json_body(&mut response);
+            for bcast in result["broadcasts"].as_array().unwrap() {
+                ids.push(bcast["id"].as_str().unwrap().to_owned());
+            }
+            snapshots.push(result["snapshot"].clone());
+            match result["next"].as_str() {
+                Some(link) => next = link.to_owned(),
+                None => break,
+            }
+        }
+        assert_eq!(ids, vec!["baz/quux", "foo/bar", "foo/baz"]);
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0], snapshots[1]);
+
+        // Any change to the broadcasts changes the snapshot
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::FooAlt)
+            .body("v2")
+            .dispatch();
+        let mut response = client
+            .get("/v2/broadcasts?limit=2&after=foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"][0]["id"], "foo/baz");
+        assert!(result["next"].is_null());
+        assert_ne!(result["snapshot"], snapshots[0]);
+
+        let mut response = client
+            .get("/v2/broadcasts?after=foo")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+    }
+
+    #[test]
+    fn test_events() {
+        let client = rocket_client();
+        let mut response = client.get("/v1/events").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["events"], *json!([]));
+        let acked_offset = result["acked_offset"].as_u64().unwrap();
+
+        for version in ["v1", "v2"] {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(version)
+                .dispatch();
+        }
+        {
+            let pool = client.rocket().state::<MysqlPool>().unwrap();
+            let conn = pool.get().unwrap();
+            let new_version = NewVersion {
+                version: "v1".to_owned(),
+                expires_at: Some(Utc::now().naive_utc() - Duration::seconds(1)),
+                ..Default::default()
+            };
+            Broadcaster::new(DEFAULT_NAMESPACE.to_owned(), "baz".to_owned())
+                .broadcast_new_version(&conn, "quux", &new_version, &Webhooks::default())
+                .unwrap();
+            Broadcast::expire_due(&conn, &HashMap::new(), &Webhooks::default()).unwrap();
+        }
+
+        let mut response = client.get("/v1/events").header(Auth::Reader).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["acked_offset"], acked_offset);
+        let events = result["events"].as_array().unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| {
+                (
+                    event["offset"].as_u64().unwrap() - acked_offset,
+                    event["id"].as_str().unwrap(),
+                    event["action"].as_str().unwrap(),
+                    event["version"].as_str(),
+                    event["sequence"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, "foo/bar", "update", Some("v1"), 1),
+                (2, "foo/bar", "update", Some("v2"), 2),
+                (3, "baz/quux", "update", Some("v1"), 1),
+                (4, "baz/quux", "remove", None, 1),
+            ]
+        );
+        assert!(DateTime::parse_from_rfc3339(events[0]["created"].as_str().unwrap()).is_ok());
+
+        let mut response = client
+            .get(format!("/v1/events?after={}&limit=1", acked_offset + 1))
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let events = result["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["offset"], acked_offset + 2);
+
+        // Acknowledged events are skipped by default
+        let mut response = client
+            .put(format!("/v1/events/ack/{}", acked_offset + 3))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "acked_offset": acked_offset + 3})
+        );
+        let mut response = client.get("/v1/events").header(Auth::Reader).dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["acked_offset"], acked_offset + 3);
+        let events = result["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["action"], "remove");
+
+        // Acknowledgements never move backwards
+        let mut response = client
+            .put(format!("/v1/events/ack/{}", acked_offset + 1))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(json_body(&mut response)["acked_offset"], acked_offset + 3);
+
+        let mut response = client
+            .put(format!("/v1/events/ack/{}", acked_offset + 5))
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut response)["errno"], 106);
+        let response = client.get("/v1/events").header(Auth::Foo).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn test_prune_events() {
+        let client = rocket_client();
+        for version in ["v1", "v2"] {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body(version)
+                .dispatch();
+        }
+        let mut response = client
+            .get("/v1/events?after=0&limit=10000")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let offsets: Vec<_> = result["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["offset"].as_u64().unwrap())
+            .collect();
+        let last = offsets[offsets.len() - 1];
+        let _ = client
+            .put(format!("/v1/events/ack/{}", last - 1))
+            .header(Auth::Reader)
+            .dispatch();
+
+        // The reader's unacknowledged event outlives the retention window
+        let pool = client.rocket().state::<MysqlPool>().unwrap();
+        let conn = pool.get().unwrap();
+        let pruned =
+            BroadcastEvent::prune(&conn, Utc::now().naive_utc() + Duration::days(1)).unwrap();
+        assert!(pruned >= offsets.len() - 1);
+        let reader = Reader::new(DEFAULT_NAMESPACE.to_owned(), "reader".to_owned());
+        let remaining: Vec<_> = reader
+            .read_events(&conn, 0, 10000)
+            .unwrap()
+            .iter()
+            .map(|event| event.offset)
+            .collect();
+        assert_eq!(remaining, vec![last]);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let client = rocket_client_with(vec![(
+            "auth_namespaces",
+            to_table(["foo=default,stage", "reader=*"].to_vec()).into(),
+        )]);
+        for (path, version) in [
+            ("/v1/ns/stage/broadcasts/foo/bar", "v1"),
+            ("/v1/ns/stage/broadcasts/foo/baz", "v2"),
+            ("/v1/broadcasts/foo/bar", "v0"),
+        ] {
+            let response = client.put(path).header(Auth::Foo).body(version).dispatch();
+            assert_eq!(response.status(), Status::Created);
+        }
+        let response = client
+            .put("/v1/ns/stage/broadcasts/baz/quux")
+            .header(Auth::Baz)
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let mut response = client
+            .get("/v1/ns/stage/broadcasts")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"foo/bar": "v1", "foo/baz": "v2"}})
+        );
+        let mut response = client.get("/v1/broadcasts").header(Auth::Reader).dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"foo/bar": "v0"}})
+        );
+        let mut response = client
+            .get("/v1/ns/default/broadcasts")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {"foo/bar": "v0"}})
+        );
+        let mut response = client
+            .get("/v1/ns/stage/broadcasts/foo/bar")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(json_body(&mut response)["broadcast"]["version"], "v1");
+        let mut response = client
+            .get("/v1/ns/stage/broadcasts?limit=1")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response)["next"],
+            "/v1/ns/stage/broadcasts?limit=1&after=foo/bar"
+        );
+        let mut response = client
+            .get("/v2/ns/stage/broadcasts?limit=1")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"][0]["id"], "foo/bar");
+        assert_eq!(result["broadcasts"][0]["version"], "v1");
+        assert_eq!(
+            result["next"],
+            "/v2/ns/stage/broadcasts?limit=1&after=foo/bar"
+        );
+        let mut response = client
+            .get(result["next"].as_str().unwrap())
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        assert_eq!(result["broadcasts"][0]["id"], "foo/baz");
+        assert!(result["next"].is_null());
+        let mut response = client
+            .get("/v1/ns/test/broadcasts")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(
+            json_body(&mut response),
+            *json!({"code": 200, "broadcasts": {}})
+        );
+
+        let mut response = client
+            .get("/v1/ns/stage/events")
+            .header(Auth::Reader)
+            .dispatch();
+        let result = json_body(&mut response);
+        let events: Vec<_> = result["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["id"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(events, vec!["foo/bar", "foo/baz"]);
+
+        let response = client
+            .get("/v1/ns/stage/pending/foo")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/v1/ns/stage/broadcasts")
+            .header(Auth::Foo)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .get("/v1/ns/st.age/broadcasts")
+            .header(Auth::Reader)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let client = rocket_client();
+        let response = client.get("/__metrics__").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let client = rocket_client_with(vec![("prometheus_metrics", true.into())]);
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let mut response = client.get("/__metrics__").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::with_params(
+                "text",
+                "plain",
+                ("version", "0.0.4")
+            ))
+        );
+        let body = response.body_string().unwrap();
+        assert!(body.contains("# TYPE megaphone_broadcast_cmd_update_total counter\n"));
+        assert!(body.contains("# TYPE megaphone_broadcast_update_milliseconds histogram\n"));
+        assert!(body.contains("\nmegaphone_db_pool_connections"));
+        assert!(body.contains("\nmegaphone_db_pool_idle_connections"));
+        assert!(body.contains("\nmegaphone_db_pool_max_size 1\n"));
+        assert!(body.contains("\nmegaphone_db_pool_checkout_milliseconds_count 1\n"));
+    }
+
+    #[test]
+    fn test_request_metrics() {
+        let client = rocket_client_with(vec![("prometheus_metrics", true.into())]);
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .body("v1")
+            .dispatch();
+        let _ = client.get("/v1/broadcasts").dispatch();
+        let _ = client.get("/v1/broadcasts").dispatch();
+        let _ = client.get("/v1/nonexistent").dispatch();
+        let mut response = client.get("/__metrics__").dispatch();
+        let body = response.body_string().unwrap();
+        for line in [
+            r#"megaphone_request_total{method="PUT",route="broadcast",status="2xx"} 1"#,
+            r#"megaphone_request_total{method="GET",route="get_broadcasts",status="4xx"} 2"#,
+            r#"megaphone_request_total{method="GET",route="unmatched",status="4xx"} 1"#,
+            r#"megaphone_request_time_milliseconds_count{method="GET",route="get_broadcasts",status="4xx"} 2"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_user_agent_tags() {
+        let client = rocket_client_with(vec![("prometheus_metrics", true.into())]);
+        let _ = client
+            .get("/v1/broadcasts")
+            .header(Header::new("User-Agent", "autopush/1.68.0"))
+            .dispatch();
+        let _ = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(Header::new("User-Agent", "python-requests/2.31.0"))
+            .body("v1")
+            .dispatch();
+        let mut response = client.get("/__metrics__").dispatch();
+        let body = response.body_string().unwrap();
+        for line in [
+            r#"megaphone_request_total{method="GET",route="get_broadcasts",status="4xx",ua_browser_family="Other",ua_client="autopush",ua_client_ver="1",ua_os_family="Other"} 1"#,
+            r#"megaphone_broadcast_cmd_update_total{broadcaster="foo",channel_id="bar",namespace="default",ua_browser_family="Other",ua_client="python-requests",ua_client_ver="2",ua_os_family="Other"} 1"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_error_metrics() {
+        let client = rocket_client_with(vec![
+            ("prometheus_metrics", true.into()),
+            ("error_metrics_interval", 3600.into()),
+        ]);
+        for _ in 0..3 {
+            let _ = client
+                .get("/v1/broadcasts")
+                .header(Header::new("Authorization", "Bearer nope"))
+                .dispatch();
+        }
+        let _ = client.get("/v1/broadcasts").header(Auth::Foo).dispatch();
+        let metrics =
+            |client: &Client| client.get("/__metrics__").dispatch().body_string().unwrap();
+        // Aggregated until flushed
+        assert!(!metrics(&client).contains("megaphone_error_total"));
+        client.rocket().state::<ErrorMetrics>().unwrap().flush();
+        let body = metrics(&client);
+        for line in [
+            r#"megaphone_error_total{errno="121",status="401"} 3"#,
+            r#"megaphone_error_total{errno="122",status="403"} 1"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_metric_tags() {
+        let metric_tags = |client: &Client| {
+            let _ = client
+                .put("/v1/broadcasts/foo/bar")
+                .header(Auth::Foo)
+                .body("v1")
+                .dispatch();
+            let body = client.get("/__metrics__").dispatch().body_string().unwrap();
+            body.lines()
+                .find(|l| l.starts_with("megaphone_broadcast_cmd_update_total{"))
+                .unwrap()
+                .to_owned()
+        };
+        let client = rocket_client_with(vec![("prometheus_metrics", true.into())]);
+        assert_eq!(
+            metric_tags(&client),
+            r#"megaphone_broadcast_cmd_update_total{broadcaster="foo",channel_id="bar",namespace="default"} 1"#
+        );
+
+        let mut static_tags: BTreeMap<String, RValue> = BTreeMap::new();
+        static_tags.insert("env".to_owned(), "test".into());
+        let mut buckets = BTreeMap::new();
+        buckets.insert("broadcaster".to_owned(), RValue::Array(vec!["baz".into()]));
+        let client = rocket_client_with(vec![
+            ("prometheus_metrics", true.into()),
+            ("metric_tags", static_tags.into()),
+            ("metric_tag_allowlist", vec!["broadcaster"].into()),
+            ("metric_tag_buckets", buckets.into()),
+        ]);
+        assert_eq!(
+            metric_tags(&client),
+            r#"megaphone_broadcast_cmd_update_total{broadcaster="other",env="test"} 1"#
+        );
+        // The fixed tags aren't subject to the allowlist
+        let body = client.get("/__metrics__").dispatch().body_string().unwrap();
+        let line =
+            r#"megaphone_request_total{env="test",method="PUT",route="broadcast",status="2xx"} 1"#;
+        assert!(body.lines().any(|l| l == line), "missing {:?}", line);
+    }
+
+    #[test]
+    fn test_tracing() {
+        let path = std::env::temp_dir().join("megaphone-test_tracing.json");
+        let _ = std::fs::remove_file(&path);
+        let (url, received) = stand_in(vec![200]);
+        let mut webhooks = BTreeMap::new();
+        webhooks.insert("standin".to_owned(), subscriber(&url, "s3cr3t", None));
+        let client = rocket_client_with(vec![
+            ("trace_exporter", "file".into()),
+            ("trace_file", path.to_str().unwrap().into()),
+            ("webhooks", RValue::Table(webhooks)),
+            ("webhook_interval", RValue::from(3600)),
+        ]);
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let response = client
+            .put("/v1/broadcasts/foo/bar")
+            .header(Auth::Foo)
+            .header(Header::new(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            ))
+            .body("v1")
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+
+        // The delivery continues the trace
+        let deliverer = WebhookDeliverer::from_config(
+            client.rocket().config(),
+            client.rocket().state::<Webhooks>().unwrap().clone(),
+            client.rocket().state::<MysqlPool>().unwrap().clone(),
+            (**client.rocket().state::<RequestLogger>().unwrap()).clone(),
+            client.rocket().state::<Metrics>().unwrap().clone(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(deliverer.deliver_due().unwrap(), 1);
+        let request = received.recv().unwrap();
+        assert!(request
+            .header("traceparent")
+            .unwrap()
+            .starts_with(&format!("00-{}-", trace_id)));
+
+        client
+            .rocket()
+            .state::<Option<Tracing>>()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .force_flush();
+        // Other tests' requests may share the global TracerProvider
+        let spans: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .flat_map(|line| {
+                let batch: Value = serde_json::from_str(line).unwrap();
+                batch["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .filter(|span| span["traceId"] == trace_id)
+            .collect();
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span["name"] == name)
+                .unwrap_or_else(|| panic!("missing span {:?} in {:?}", name, spans))
+        };
+        let request = span("PUT /v1/broadcasts/<broadcaster_id>/<bchannel_id>");
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request["kind"], 2);
+        for name in ["auth.broadcaster", "db.pool.checkout"] {
+            assert_eq!(span(name)["parentSpanId"], request["spanId"]);
+        }
+        let query = span("INSERT");
+        assert!(query["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|attribute| attribute["key"] == "db.statement"));
+        span("webhook.deliver");
+    }
+
+    #[test]
+    fn test_version() {
+        let client = rocket_client();
+        let mut response = client.get("/__version__").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["version"], "devel");
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let client = rocket_client();
+        let mut response = client.get("/__heartbeat__").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
         assert_eq!(result["code"], 200);
+        assert_eq!(result["status"], "ok");
+        assert_eq!(result["database"], "ok");
+        for name in ["pool", "database", "migrations"] {
+            assert_eq!(result["checks"][name]["status"], "ok", "{}", name);
+            assert!(result["checks"][name]["time_ms"].is_f64(), "{}", name);
+        }
+        assert_eq!(
+            result["checks"]["pool"]["message"],
+            "0 of 1 connections in use"
+        );
+        assert!(result["checks"].get("statsd").is_none());
+
+        // Any latency degrades the database
+        let client = rocket_client_with(vec![("heartbeat_database_latency_warn", 0.into())]);
+        let mut response = client.get("/__heartbeat__").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["status"], "error");
+        assert_eq!(result["database"], "error");
+        assert_eq!(result["checks"]["database"]["status"], "warn");
+
+        // A saturated pool doesn't make the heartbeat unavailable by default
+        let client = rocket_client_with(vec![
+            ("heartbeat_pool_saturation_warn", 0.into()),
+            ("heartbeat_pool_saturation_fail", 0.into()),
+        ]);
+        let mut response = client.get("/__heartbeat__").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result = json_body(&mut response);
+        assert_eq!(result["status"], "error");
         assert_eq!(result["database"], "ok");
+        assert_eq!(result["checks"]["pool"]["status"], "fail");
+
+        let mut unavailable_on = BTreeMap::new();
+        unavailable_on.insert("database".to_owned(), RValue::from("warn"));
+        let client = rocket_client_with(vec![
+            ("heartbeat_database_latency_warn", 0.into()),
+            ("heartbeat_unavailable_on", unavailable_on.into()),
+        ]);
+        let mut response = client.get("/__heartbeat__").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let result = json_body(&mut response);
+        assert_eq!(result["code"], 503);
+        assert_eq!(result["database"], "error");
     }
 
     #[test]
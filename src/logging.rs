@@ -1,8 +1,31 @@
 /// Logging via slog
 ///
 /// Provides a RequestLogger with moz log fields per request
-use std::io;
+///
+/// Records below `log_level` (default `info`) are dropped, unless their
+/// module has its own level in the `log_module_levels` table (the longest
+/// matching module path wins), e.g.
+///
+/// ```toml
+/// [development]
+/// log_level = "warning"
+///
+/// [development.log_module_levels]
+/// "megaphone::webhooks" = "debug"
+/// ```
+///
+/// `log_output` selects where records are written: `stdout` (the default),
+/// `file` (the `log_file` path) or `both`. The log file is rotated when it
+/// exceeds `log_file_max_size` bytes, keeping `log_file_max_files` previous
+/// files (`<log_file>.1` being the most recent).
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 use std::ops::Deref;
+use std::panic::UnwindSafe;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 use lazy_static::lazy_static;
 use mozsvc_common::{aws::get_ec2_instance_id, get_hostname};
@@ -14,24 +37,39 @@ use rocket::{
     Config, Request, State,
 };
 use sentry_slog::SentryDrain;
-use slog::{self, slog_o, Drain};
+use slog::{self, slog_o, Drain, Level, OwnedKVList, Record, SendSyncRefUnwindSafeDrain};
 use slog_derive::KV;
 use slog_mozlog_json::MozLogJson;
 
 use crate::error::{HandlerError, HandlerResult};
+use crate::request_id::RequestId;
+use crate::telemetry;
 
 lazy_static! {
     static ref LOGGER_NAME: String =
         format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
     static ref MSG_TYPE: String = format!("{}:log", env!("CARGO_PKG_NAME"));
+    /// The open log files (shared by every logger writing to them)
+    static ref LOG_FILES: Mutex<HashMap<PathBuf, Arc<Mutex<RotatingFile>>>> =
+        Mutex::new(HashMap::new());
 }
 
+/// Default `log_file_max_size` (10 MiB)
+const DEFAULT_LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default `log_file_max_files`
+const DEFAULT_LOG_FILE_MAX_FILES: u32 = 5;
+
+type BoxDrain = Box<dyn Drain<Ok = (), Err = slog::Never> + Send>;
+
 #[derive(Clone, KV)]
 struct MozLogFields {
     method: &'static str,
     path: String,
     remote: Option<String>,
     agent: Option<String>,
+    request_id: String,
+    trace_id: Option<String>,
 }
 
 impl MozLogFields {
@@ -45,6 +83,8 @@ impl MozLogFields {
                 .get_one("X-Forwarded-For")
                 .map(str::to_owned)
                 .or_else(|| request.remote().map(|addr| addr.ip().to_string())),
+            request_id: RequestId::from_request(request).0,
+            trace_id: telemetry::trace_id(),
         }
     }
 }
@@ -81,23 +121,319 @@ impl<'a, 'r> FromRequest<'a, 'r> for RequestLogger {
     }
 }
 
+/// Drops the records below the level of their module
+struct LevelFilter<D> {
+    drain: D,
+    level: Level,
+    /// Levels of modules, longest module paths first
+    modules: Vec<(String, Level)>,
+}
+
+impl<D> LevelFilter<D> {
+    fn level_for(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .find(|(path, _)| {
+                module == path
+                    || (module.starts_with(path.as_str()) && module[path.len()..].starts_with("::"))
+            })
+            .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+impl<D: Drain> Drain for LevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), D::Err> {
+        if record.level().is_at_least(self.level_for(record.module())) {
+            self.drain.log(record, values)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the root logger dropping the records below the level of their
+/// module, passing the others to Sentry (if `sentry`) and then `drain`
+fn filtered_logger<D>(
+    drain: D,
+    sentry: bool,
+    level: Level,
+    modules: Vec<(String, Level)>,
+) -> slog::Logger
+where
+    D: SendSyncRefUnwindSafeDrain<Ok = (), Err = slog::Never> + UnwindSafe + 'static,
+{
+    if sentry {
+        let drain = SentryDrain::new(drain);
+        slog::Logger::root(
+            LevelFilter {
+                drain,
+                level,
+                modules,
+            },
+            slog_o!(),
+        )
+    } else {
+        slog::Logger::root(
+            LevelFilter {
+                drain,
+                level,
+                modules,
+            },
+            slog_o!(),
+        )
+    }
+}
+
+/// A log file, rotated when exceeding its maximum size
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Maximum size in bytes (0 for no rotation)
+    max_size: u64,
+    /// Number of rotated files kept
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: u32) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *self = RotatingFile::open(&self.path, self.max_size, self.max_files)?;
+        Ok(())
+    }
+
+    /// Write complete records (lines), rotating beforehand if need be
+    fn write_records(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// A writer of a shared RotatingFile
+///
+/// Writes are buffered up to the end of their line, so that records of
+/// different loggers don't interleave and aren't split by a rotation.
+struct LogFileWriter {
+    file: Arc<Mutex<RotatingFile>>,
+    buf: Vec<u8>,
+}
+
+impl LogFileWriter {
+    fn open(path: &Path, max_size: u64, max_files: u32) -> io::Result<LogFileWriter> {
+        let mut files = LOG_FILES.lock().unwrap_or_else(|e| e.into_inner());
+        let file = match files.get(path) {
+            Some(file) => file.clone(),
+            None => {
+                let file = Arc::new(Mutex::new(RotatingFile::open(path, max_size, max_files)?));
+                files.insert(path.to_owned(), file.clone());
+                file
+            }
+        };
+        Ok(LogFileWriter {
+            file,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if let Some(end) = self.buf.iter().rposition(|b| *b == b'\n') {
+            let records: Vec<u8> = self.buf.drain(..=end).collect();
+            self.file
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_records(&records)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .file
+            .flush()
+    }
+}
+
+/// Where records are written
+#[derive(Debug, PartialEq)]
+enum LogOutput {
+    Stdout,
+    File,
+    Both,
+}
+
+/// Logging settings of the rocket Config
+#[derive(Debug)]
+struct LogConfig {
+    json: bool,
+    level: Level,
+    modules: Vec<(String, Level)>,
+    output: LogOutput,
+    file: Option<PathBuf>,
+    file_max_size: u64,
+    file_max_files: u32,
+}
+
+fn parse_level(level: &str, name: &str) -> HandlerResult<Level> {
+    Level::from_str(level).map_err(|_| {
+        HandlerError::internal(format!(
+            "Invalid {}: {:?} (expected critical, error, warning, info, debug or trace)",
+            name, level
+        ))
+    })
+}
+
+impl LogConfig {
+    fn from_config(config: &Config) -> HandlerResult<LogConfig> {
+        let json = match config.get_bool("json_logging") {
+            Ok(json_logging) => json_logging,
+            Err(ConfigError::Missing(_)) => true,
+            Err(e) => {
+                return Err(HandlerError::internal(format!(
+                    "Invalid ROCKET_JSON_LOGGING: {}",
+                    e
+                )))
+            }
+        };
+        let level = match config.get_str("log_level") {
+            Ok(level) => parse_level(level, "ROCKET_LOG_LEVEL")?,
+            Err(ConfigError::Missing(_)) => Level::Info,
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_LOG_LEVEL: {}",
+                e
+            )))?,
+        };
+        let mut modules = Vec::new();
+        match config.get_table("log_module_levels") {
+            Ok(table) => {
+                for (module, level) in table {
+                    let level = level.as_str().ok_or_else(|| {
+                        HandlerError::internal(format!(
+                            "Invalid log_module_levels value for: {:?}",
+                            module
+                        ))
+                    })?;
+                    modules.push((module.to_owned(), parse_level(level, module)?));
+                }
+            }
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_LOG_MODULE_LEVELS: {}",
+                e
+            )))?,
+        }
+        modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        let output = match config.get_str("log_output") {
+            Ok("stdout") | Err(ConfigError::Missing(_)) => LogOutput::Stdout,
+            Ok("file") => LogOutput::File,
+            Ok("both") => LogOutput::Both,
+            Ok(output) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_LOG_OUTPUT: {:?} (expected stdout, file or both)",
+                output
+            )))?,
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_LOG_OUTPUT: {}",
+                e
+            )))?,
+        };
+        let file = match output {
+            LogOutput::Stdout => None,
+            LogOutput::File | LogOutput::Both => {
+                Some(PathBuf::from(config.get_str("log_file").map_err(|_| {
+                    HandlerError::internal("Invalid or undefined ROCKET_LOG_FILE".to_owned())
+                })?))
+            }
+        };
+        let file_max_size = match config.get_int("log_file_max_size") {
+            Ok(size) if size >= 0 => size as u64,
+            Err(ConfigError::Missing(_)) => DEFAULT_LOG_FILE_MAX_SIZE,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_LOG_FILE_MAX_SIZE".to_owned(),
+            ))?,
+        };
+        let file_max_files = match config.get_int("log_file_max_files") {
+            Ok(files) if (0..=i64::from(u32::MAX)).contains(&files) => files as u32,
+            Err(ConfigError::Missing(_)) => DEFAULT_LOG_FILE_MAX_FILES,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_LOG_FILE_MAX_FILES".to_owned(),
+            ))?,
+        };
+        Ok(LogConfig {
+            json,
+            level,
+            modules,
+            output,
+            file,
+            file_max_size,
+            file_max_files,
+        })
+    }
+}
+
+/// Return the drain formatting records (as JSON or text) to `writer`
+fn format_drain<W: Write + Send + 'static>(writer: W, hostname: Option<&str>) -> BoxDrain {
+    match hostname {
+        Some(hostname) => Box::new(
+            MozLogJson::new(writer)
+                .logger_name(LOGGER_NAME.to_owned())
+                .msg_type(MSG_TYPE.to_owned())
+                .hostname(hostname.to_owned())
+                .build()
+                .fuse(),
+        ),
+        None => {
+            let decorator = slog_term::PlainDecorator::new(writer);
+            Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+        }
+    }
+}
+
 pub fn init_logging(
     config: &Config,
     sentry: &Option<sentry::ClientInitGuard>,
 ) -> HandlerResult<RequestLogger> {
-    let json_logging = match config.get_bool("json_logging") {
-        Ok(json_logging) => json_logging,
-        Err(ConfigError::Missing(_)) => true,
-        Err(e) => {
-            return Err(HandlerError::internal(format!(
-                "Invalid ROCKET_JSON_LOGGING: {}",
-                e
-            )))
-        }
-    };
+    let log_config = LogConfig::from_config(config)?;
 
-    let async_drain = if json_logging {
-        let hostname = match get_ec2_instance_id() {
+    let hostname = if log_config.json {
+        Some(match get_ec2_instance_id() {
             Some(v) => v.to_owned(),
             None => match get_hostname() {
                 Ok(v) => v.to_string_lossy().to_string(),
@@ -108,21 +444,41 @@ pub fn init_logging(
                     )))
                 }
             },
-        };
-
-        let drain = MozLogJson::new(io::stdout())
-            .logger_name(LOGGER_NAME.to_owned())
-            .msg_type(MSG_TYPE.to_owned())
-            .hostname(hostname)
-            .build()
-            .fuse();
-        slog_async::Async::new(drain).build().fuse()
+        })
     } else {
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::FullFormat::new(decorator).build().fuse();
-        slog_async::Async::new(drain).build().fuse()
+        None
     };
 
+    let stdout_drain: Option<BoxDrain> = match log_config.output {
+        LogOutput::File => None,
+        LogOutput::Stdout | LogOutput::Both => Some(match hostname {
+            Some(ref hostname) => format_drain(io::stdout(), Some(hostname)),
+            None => {
+                let decorator = slog_term::TermDecorator::new().build();
+                Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+            }
+        }),
+    };
+    let file_drain: Option<BoxDrain> = match log_config.file {
+        Some(ref path) => {
+            let writer =
+                LogFileWriter::open(path, log_config.file_max_size, log_config.file_max_files)
+                    .map_err(|e| {
+                        HandlerError::internal(format!("Could not open log file {:?}: {}", path, e))
+                    })?;
+            Some(format_drain(writer, hostname.as_deref()))
+        }
+        None => None,
+    };
+    let drain: BoxDrain = match (stdout_drain, file_drain) {
+        (Some(stdout_drain), Some(file_drain)) => {
+            Box::new(slog::Duplicate::new(stdout_drain, file_drain).ignore_res())
+        }
+        (Some(drain), None) | (None, Some(drain)) => drain,
+        (None, None) => unreachable!("No log output"),
+    };
+    let async_drain = slog_async::Async::new(drain).build().fuse();
+
     /* By default, only `panic!()` messages are captured and sent to sentry.
        Setting a drain doesn't always capture other errors.
 
@@ -135,11 +491,102 @@ pub fn init_logging(
       while it doesn't report an error, it also doesn't send anything through.
 
     */
-    let logger = if sentry.is_some() {
+    if sentry.is_some() {
         dbg!("Connecting to sentry...");
-        slog::Logger::root(SentryDrain::new(async_drain).fuse(), slog_o!())
-    } else {
-        slog::Logger::root(async_drain, slog_o!())
-    };
-    Ok(RequestLogger(logger))
+    }
+    Ok(RequestLogger(filtered_logger(
+        async_drain,
+        sentry.is_some(),
+        log_config.level,
+        log_config.modules,
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Write;
+
+    use rocket::config::{Config, Environment, Value};
+    use slog::{debug, error, info, Discard, Level};
+
+    use super::{filtered_logger, LevelFilter, LogConfig, LogFileWriter, LogOutput};
+
+    #[test]
+    fn test_config() {
+        let config = Config::build(Environment::Development).unwrap();
+        let log_config = LogConfig::from_config(&config).unwrap();
+        assert_eq!(log_config.level, Level::Info);
+        assert_eq!(log_config.output, LogOutput::Stdout);
+        assert!(log_config.file.is_none());
+
+        let mut modules = BTreeMap::new();
+        modules.insert("megaphone".to_owned(), Value::from("warning"));
+        modules.insert("megaphone::webhooks".to_owned(), Value::from("trace"));
+        let config = Config::build(Environment::Development)
+            .extra("log_level", "error")
+            .extra("log_module_levels", modules)
+            .unwrap();
+        let log_config = LogConfig::from_config(&config).unwrap();
+        let filter = LevelFilter {
+            drain: Discard,
+            level: log_config.level,
+            modules: log_config.modules,
+        };
+        assert_eq!(filter.level_for("megaphone::webhooks"), Level::Trace);
+        assert_eq!(filter.level_for("megaphone::webhooks::retry"), Level::Trace);
+        assert_eq!(filter.level_for("megaphone::webhooks2"), Level::Warning);
+        assert_eq!(filter.level_for("megaphone::http"), Level::Warning);
+        assert_eq!(filter.level_for("rocket::server"), Level::Error);
+
+        for (name, value) in [
+            ("log_level", "loud"),
+            ("log_output", "syslog"),
+            ("log_output", "both"),
+        ] {
+            let config = Config::build(Environment::Development)
+                .extra(name, value)
+                .unwrap();
+            assert!(LogConfig::from_config(&config).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_sentry_level() {
+        let logger = filtered_logger(Discard, true, Level::Info, Vec::new());
+        let events = sentry::test::with_captured_events(|| {
+            debug!(logger, "quiet");
+            info!(logger, "loud");
+            error!(logger, "failed");
+        });
+        assert_eq!(events.len(), 1);
+        let breadcrumbs: Vec<_> = events[0]
+            .breadcrumbs
+            .iter()
+            .map(|breadcrumb| breadcrumb.message.as_deref())
+            .collect();
+        assert_eq!(breadcrumbs, vec![Some("loud")]);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join("megaphone-test_rotation");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("megaphone.log");
+        let mut writer = LogFileWriter::open(&path, 10, 2).unwrap();
+        // Shared with the other writers of the file
+        let mut other = LogFileWriter::open(&path, 10, 2).unwrap();
+        writer.write_all(b"first").unwrap();
+        other.write_all(b"second\n").unwrap();
+        writer.write_all(b" line\n").unwrap();
+        writer.write_all(b"third\nfourth\n").unwrap();
+        writer.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("megaphone.log"), "third\nfourth\n");
+        assert_eq!(read("megaphone.log.1"), "first line\n");
+        assert_eq!(read("megaphone.log.2"), "second\n");
+        assert!(!dir.join("megaphone.log.3").exists());
+    }
 }
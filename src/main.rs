@@ -8,12 +8,26 @@ extern crate diesel_migrations;
 extern crate rocket;
 
 mod auth;
+mod compression;
 mod db;
 mod error;
+mod format;
+mod health;
 mod http;
 mod logging;
 mod metrics;
+mod namespace;
+mod prometheus;
+mod reporting;
+mod request_id;
+mod shutdown;
+mod signing;
+mod sweeper;
 mod tags;
+mod telemetry;
+mod user_agent;
+mod version_policy;
+mod webhooks;
 
 fn main() {
     http::rocket().expect("rocket failed").launch();
@@ -1,21 +1,30 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::mem;
 use std::net::UdpSocket;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use cadence::{
-    BufferedUdpMetricSink, CountedExt, Metric, NopMetricSink, QueuingMetricSink, StatsdClient,
-    StatsdClientBuilder, Timed,
+    BufferedUdpMetricSink, Counted, CountedExt, Gauged, Histogrammed, Metric, MetricSink,
+    NopMetricSink, QueuingMetricSink, StatsdClient, StatsdClientBuilder, Timed,
 };
 use rocket::{
     config::ConfigError,
+    fairing::{Fairing, Info, Kind},
+    http::Status,
     request::{self, FromRequest},
-    Config, Outcome, Request, State,
+    Config, Data, Outcome, Request, Response, State,
 };
 use slog::{error, trace, warn, Logger};
 
-use crate::error::{self, HandlerError};
+use crate::error::{self, HandlerError, HandlerResult};
 use crate::logging;
-use crate::tags::Tags;
+use crate::prometheus::{PrometheusMetricSink, PrometheusRegistry};
+use crate::shutdown::Shutdown;
+use crate::tags::{TagPolicy, Tags};
 
 #[derive(Debug, Clone)]
 pub struct MetricTimer {
@@ -28,8 +37,81 @@ pub struct MetricTimer {
 pub struct Metrics {
     client: Option<Arc<StatsdClient>>,
     tags: Option<Tags>,
+    policy: TagPolicy,
     log: Logger,
     timer: Option<MetricTimer>,
+    prometheus: Option<PrometheusRegistry>,
+    statsd: Option<Arc<SinkStats>>,
+}
+
+/// Counts of the metrics emitted to statsd
+#[derive(Debug, Default)]
+pub struct SinkStats {
+    emitted: AtomicU64,
+    errors: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl SinkStats {
+    /// Return the number of metrics emitted and of failures to emit them
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.emitted.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Records the outcome of emitting metrics in SinkStats
+struct CountingMetricSink<S> {
+    sink: S,
+    stats: Arc<SinkStats>,
+}
+
+impl<S: MetricSink> MetricSink for CountingMetricSink<S> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let result = self.sink.emit(metric);
+        match &result {
+            Ok(_) => self.stats.emitted.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                *self
+                    .stats
+                    .last_error
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner()) = Some(e.to_string());
+                self.stats.errors.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+        result
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Emits metrics to both statsd and Prometheus
+struct TeeMetricSink<A, B>(A, B);
+
+impl<A: MetricSink, B: MetricSink> MetricSink for TeeMetricSink<A, B> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let written = self.0.emit(metric);
+        self.1.emit(metric)?;
+        written
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let flushed = self.0.flush();
+        self.1.flush()?;
+        flushed
+    }
 }
 
 impl Drop for Metrics {
@@ -76,6 +158,18 @@ impl Metrics {
         sentry: &Option<sentry::ClientInitGuard>,
     ) -> error::HandlerResult<Metrics> {
         let logging = logging::init_logging(config, sentry)?;
+        let prometheus = match config.get_bool("prometheus_metrics") {
+            Ok(true) => Some(PrometheusRegistry::default()),
+            Ok(false) | Err(ConfigError::Missing(_)) => None,
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_PROMETHEUS_METRICS: {}",
+                e
+            )))?,
+        };
+        let label = config
+            .get_string("statsd_label")
+            .unwrap_or("megaphone".to_string());
+        let statsd_stats = Arc::new(SinkStats::default());
         let builder = match config.get_string("statsd_host") {
             Ok(statsd_host) => {
                 let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| {
@@ -92,15 +186,24 @@ impl Metrics {
                 let udp_sink = BufferedUdpMetricSink::from(host, socket).map_err(|e| {
                     HandlerError::internal(format!("Could not start server {:?}", e))
                 })?;
-                let sink = QueuingMetricSink::from(udp_sink);
-                StatsdClient::builder(
-                    &config
-                        .get_string("statsd_label")
-                        .unwrap_or("megaphone".to_string()),
-                    sink,
-                )
+                let sink = QueuingMetricSink::from(CountingMetricSink {
+                    sink: udp_sink,
+                    stats: statsd_stats.clone(),
+                });
+                match &prometheus {
+                    Some(registry) => StatsdClient::builder(
+                        &label,
+                        TeeMetricSink(sink, PrometheusMetricSink::new(registry.clone())),
+                    ),
+                    None => StatsdClient::builder(&label, sink),
+                }
             }
-            Err(ConfigError::Missing(_)) => Self::sink(),
+            Err(ConfigError::Missing(_)) => match &prometheus {
+                Some(registry) => {
+                    StatsdClient::builder(&label, PrometheusMetricSink::new(registry.clone()))
+                }
+                None => Self::sink(),
+            },
             Err(e) => {
                 error!(logging, "Could not build metric: {:?}", e);
                 return Err(error::HandlerError::internal(format!(
@@ -118,9 +221,37 @@ impl Metrics {
             log: logging.clone(),
             timer: None,
             tags: Some(Tags::init(config)?),
+            policy: TagPolicy::from_config(config)?,
+            prometheus,
+            statsd: config.get_str("statsd_host").ok().map(|_| statsd_stats),
         })
     }
 
+    /// Return the statsd sink's SinkStats, if statsd is enabled
+    pub fn statsd_stats(&self) -> Option<&SinkStats> {
+        self.statsd.as_deref()
+    }
+
+    /// Return the Prometheus registry, if Prometheus metrics are enabled
+    pub fn prometheus(&self) -> Option<&PrometheusRegistry> {
+        self.prometheus.as_ref()
+    }
+
+    /// Return the tags of a metric: its own tags limited by the TagPolicy,
+    /// along with the static tags
+    fn tags_for(&self, tags: Option<Tags>) -> BTreeMap<String, String> {
+        let mut mtags: BTreeMap<String, String> = tags
+            .map(|tags| tags.tags)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(key, val)| Some((key.clone(), self.policy.apply(&key, &val)?)))
+            .collect();
+        if let Some(tags) = self.tags.as_ref() {
+            mtags.extend(tags.tags.clone());
+        }
+        mtags
+    }
+
     // increment a counter with no tags data.
     pub fn incr(&self, label: &str) {
         self.incr_with_tags(label, None)
@@ -129,14 +260,9 @@ impl Metrics {
     pub fn incr_with_tags(&self, label: &str, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
             let mut tagged = client.incr_with_tags(label);
-            let mut mtags = self.tags.clone().unwrap_or_default();
-            if let Some(tags) = tags {
-                mtags.extend(tags.tags);
-            }
-            for key in mtags.tags.keys().clone() {
-                if let Some(val) = mtags.tags.get(key) {
-                    tagged = tagged.with_tag(key, val.as_ref());
-                }
+            let mtags = self.tags_for(tags);
+            for (key, val) in mtags.iter() {
+                tagged = tagged.with_tag(key, val);
             }
             // Include any "hard coded" tags.
             // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
@@ -150,22 +276,69 @@ impl Metrics {
         }
     }
 
+    pub fn count_with_tags(&self, label: &str, count: i64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mut tagged = client.count_with_tags(label, count);
+            let mtags = self.tags_for(tags);
+            for (key, val) in mtags.iter() {
+                tagged = tagged.with_tag(key, val);
+            }
+            match tagged.try_send() {
+                Err(e) => {
+                    // eat the metric, but log the error
+                    warn!(self.log, "⚠️ Metric {} error: {:?} ", label, e);
+                }
+                Ok(v) => trace!(self.log, "☑️ {:?}", v.as_metric_str()),
+            }
+        }
+    }
+
+    pub fn histogram_with_tags(&self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mut tagged = client.histogram_with_tags(label, value);
+            let mtags = self.tags_for(tags);
+            for (key, val) in mtags.iter() {
+                tagged = tagged.with_tag(key, val);
+            }
+            match tagged.try_send() {
+                Err(e) => {
+                    // eat the metric, but log the error
+                    warn!(self.log, "⚠️ Metric {} error: {:?} ", label, e);
+                }
+                Ok(v) => trace!(self.log, "☑️ {:?}", v.as_metric_str()),
+            }
+        }
+    }
+
+    pub fn gauge_with_tags(&self, label: &str, value: u64, tags: Option<Tags>) {
+        if let Some(client) = self.client.as_ref() {
+            let mut tagged = client.gauge_with_tags(label, value);
+            let mtags = self.tags_for(tags);
+            for (key, val) in mtags.iter() {
+                tagged = tagged.with_tag(key, val);
+            }
+            match tagged.try_send() {
+                Err(e) => {
+                    // eat the metric, but log the error
+                    warn!(self.log, "⚠️ Metric {} error: {:?} ", label, e);
+                }
+                Ok(v) => trace!(self.log, "☑️ {:?}", v.as_metric_str()),
+            }
+        }
+    }
+
     pub fn timer_with_tags(&self, label: &str, lapse: u64, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
             let mut tagged = client.time_with_tags(label, lapse);
-            let mtags = tags.unwrap_or_default();
-            for key in mtags.tags.keys().clone() {
-                if let Some(val) = mtags.tags.get(key) {
-                    tagged = tagged.with_tag(key, val.as_ref());
-                }
+            let mtags = self.tags_for(tags);
+            for (key, val) in mtags.iter() {
+                tagged = tagged.with_tag(key, val);
             }
             match tagged.try_send() {
                 Err(e) => {
                     warn!(self.log, "Metric {} error {:?}", label, e);
                 }
-                Ok(v) => {
-                    dbg!(v);
-                }
+                Ok(v) => trace!(self.log, "⌚ {:?}", v.as_metric_str()),
             }
         }
     }
@@ -184,3 +357,159 @@ impl<'a, 'r> FromRequest<'a, 'r> for Metrics {
         )
     }
 }
+
+/// Default number of seconds errors are aggregated for
+const DEFAULT_ERROR_METRICS_INTERVAL: i64 = 10;
+
+/// Counts rendered errors (`error`), tagged by their `errno` and HTTP
+/// `status`
+///
+/// Errors are aggregated per errno/status and emitted as a single counter
+/// every `error_metrics_interval` seconds from the rocket Config (default 10,
+/// 0 emits every error immediately), so a flood of identical failures
+/// doesn't overwhelm the statsd sink.
+#[derive(Clone)]
+pub struct ErrorMetrics {
+    metrics: Metrics,
+    interval: Option<Duration>,
+    pending: Arc<Mutex<HashMap<(i32, u16), i64>>>,
+}
+
+impl ErrorMetrics {
+    pub fn from_config(config: &Config, metrics: Metrics) -> HandlerResult<ErrorMetrics> {
+        let interval = match config.get_int("error_metrics_interval") {
+            Ok(interval) if interval >= 0 => interval,
+            Err(ConfigError::Missing(_)) => DEFAULT_ERROR_METRICS_INTERVAL,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_ERROR_METRICS_INTERVAL".to_owned(),
+            ))?,
+        };
+        Ok(ErrorMetrics {
+            metrics,
+            interval: Some(interval)
+                .filter(|interval| *interval > 0)
+                .map(|interval| Duration::from_secs(interval as u64)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Record a rendered error
+    pub fn record(&self, errno: i32, status: Status) {
+        if self.interval.is_none() {
+            self.emit(errno, status.code, 1);
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        *pending.entry((errno, status.code)).or_default() += 1;
+    }
+
+    /// Emit the errors aggregated since the last flush
+    pub fn flush(&self) {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            mem::take(&mut *pending)
+        };
+        for ((errno, status), count) in pending {
+            self.emit(errno, status, count);
+        }
+    }
+
+    fn emit(&self, errno: i32, status: u16, count: i64) {
+        let mut tags = Tags::default();
+        tags.tags.insert("errno".to_owned(), errno.to_string());
+        tags.tags.insert("status".to_owned(), status.to_string());
+        self.metrics.count_with_tags("error", count, Some(tags));
+    }
+
+    /// Periodically flush the aggregated errors in a background thread (if
+    /// aggregating) until `shutdown`, flushing any remaining then
+    pub fn spawn(&self, shutdown: &Shutdown) -> HandlerResult<Option<thread::JoinHandle<()>>> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Ok(None),
+        };
+        let error_metrics = self.clone();
+        let listener = shutdown.listener();
+        thread::Builder::new()
+            .name("error-metrics".to_owned())
+            .spawn(move || {
+                while listener.sleep(interval) {
+                    error_metrics.flush();
+                }
+                error_metrics.flush();
+            })
+            .map(Some)
+            .map_err(|e| HandlerError::internal(format!("Could not start error metrics: {:?}", e)))
+    }
+}
+
+/// Route tag of requests not matching any route
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// When a request started, for RequestMetrics
+struct RequestStart(Instant);
+
+/// Return the status class (e.g. `4xx`) of a Status
+fn status_class(status: Status) -> String {
+    format!("{}xx", status.code / 100)
+}
+
+/// Records the count (`request`) and latency (`request.time`) of every
+/// request, tagged by its route name, method and status class
+pub struct RequestMetrics {
+    metrics: Metrics,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Metrics) -> RequestMetrics {
+        RequestMetrics { metrics }
+    }
+}
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let route = request
+            .route()
+            .and_then(|route| route.name)
+            .unwrap_or(UNMATCHED_ROUTE);
+        let mut tags = Tags::from_request(request);
+        tags.tags.insert("route".to_owned(), route.to_owned());
+        tags.tags
+            .insert("method".to_owned(), request.method().as_str().to_owned());
+        tags.tags
+            .insert("status".to_owned(), status_class(response.status()));
+        self.metrics.incr_with_tags("request", Some(tags.clone()));
+        self.metrics.timer_with_tags(
+            "request.time",
+            (Instant::now() - start).as_millis() as u64,
+            Some(tags),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Status;
+
+    use super::status_class;
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(Status::Ok), "2xx");
+        assert_eq!(status_class(Status::Created), "2xx");
+        assert_eq!(status_class(Status::NotFound), "4xx");
+        assert_eq!(status_class(Status::ServiceUnavailable), "5xx");
+    }
+}
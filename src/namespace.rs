@@ -0,0 +1,154 @@
+/// Namespaces of broadcasts
+///
+/// Broadcasts (along with their pending versions and change events) belong
+/// to a namespace, allowing e.g. stage and test channel sets to share a
+/// deployment. Routes under `/v1/ns/<namespace>/` (or `/v2/ns/<namespace>/`)
+/// address the `<namespace>` namespace, while the remaining routes address
+/// the default namespace: `/v1/ns/stage/broadcasts/foo/bar` is
+/// `/v1/broadcasts/foo/bar` within `stage`.
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::uri::Origin,
+    request::{self, FromRequest},
+    Data, Outcome, Request,
+};
+
+use crate::error::HandlerError;
+
+/// The namespace of the routes not under `/<api version>/ns/<namespace>/`
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// API versions whose routes are namespaced
+const API_VERSIONS: [&str; 2] = ["v1", "v2"];
+
+/// Maximum length of a namespace
+const MAX_NAMESPACE_LEN: usize = 64;
+
+/// The namespace addressed by a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Namespace(pub String);
+
+impl Default for Namespace {
+    fn default() -> Namespace {
+        Namespace(DEFAULT_NAMESPACE.to_owned())
+    }
+}
+
+impl Namespace {
+    /// Whether `namespace` is a valid namespace name (url safe base64
+    /// characters)
+    pub fn is_valid(namespace: &str) -> bool {
+        !namespace.is_empty()
+            && namespace.len() <= MAX_NAMESPACE_LEN
+            && namespace
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    /// Return the namespace addressed by the request
+    pub fn from_request(request: &Request<'_>) -> Namespace {
+        request.local_cache(Namespace::default).clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Namespace {
+    type Error = HandlerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, HandlerError> {
+        Outcome::Success(Namespace::from_request(request))
+    }
+}
+
+/// Return the path of an `api_version` route (e.g. `v1` and `/broadcasts`)
+/// within `namespace`
+pub fn api_path(api_version: &str, namespace: &str, path: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        format!("/{}{}", api_version, path)
+    } else {
+        format!("/{}/ns/{}{}", api_version, namespace, path)
+    }
+}
+
+/// Split a namespaced route's path into its namespace and the equivalent
+/// path within the default namespace
+fn split_namespace(path: &str) -> Option<(&str, String)> {
+    let (api_version, rest) = path.strip_prefix('/')?.split_once('/')?;
+    if !API_VERSIONS.contains(&api_version) {
+        return None;
+    }
+    let (namespace, rest) = rest.strip_prefix("ns/")?.split_once('/')?;
+    if !Namespace::is_valid(namespace) {
+        return None;
+    }
+    Some((namespace, format!("/{}/{}", api_version, rest)))
+}
+
+/// Routes the namespaced routes to their v1 equivalents, recording the
+/// request's Namespace
+///
+/// Requests of invalid namespaces are left untouched (and so not found).
+pub struct NamespaceRouting;
+
+impl Fairing for NamespaceRouting {
+    fn info(&self) -> Info {
+        Info {
+            name: "Namespace routing",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        let (namespace, mut uri) = match split_namespace(request.uri().path()) {
+            Some((namespace, path)) => (namespace.to_owned(), path),
+            None => return,
+        };
+        if let Some(query) = request.uri().query() {
+            uri.push('?');
+            uri.push_str(query);
+        }
+        if let Ok(uri) = Origin::parse_owned(uri) {
+            request.set_uri(uri);
+            request.local_cache(|| Namespace(namespace));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{api_path, split_namespace, Namespace, DEFAULT_NAMESPACE};
+
+    #[test]
+    fn test_split_namespace() {
+        assert_eq!(
+            split_namespace("/v1/ns/stage/broadcasts/foo/bar"),
+            Some(("stage", "/v1/broadcasts/foo/bar".to_owned()))
+        );
+        assert_eq!(
+            split_namespace("/v1/ns/stage/broadcasts"),
+            Some(("stage", "/v1/broadcasts".to_owned()))
+        );
+        assert_eq!(
+            split_namespace("/v2/ns/stage/broadcasts"),
+            Some(("stage", "/v2/broadcasts".to_owned()))
+        );
+        assert_eq!(split_namespace("/v1/broadcasts/foo/bar"), None);
+        assert_eq!(split_namespace("/v3/ns/stage/broadcasts"), None);
+        assert_eq!(split_namespace("/v1/ns/stage"), None);
+        assert_eq!(split_namespace("/v1/ns//broadcasts"), None);
+        assert_eq!(split_namespace("/v1/ns/st.age/broadcasts"), None);
+        assert_eq!(
+            api_path("v1", DEFAULT_NAMESPACE, "/broadcasts"),
+            "/v1/broadcasts".to_owned()
+        );
+        assert_eq!(
+            api_path("v1", "stage", "/broadcasts"),
+            "/v1/ns/stage/broadcasts".to_owned()
+        );
+        assert_eq!(
+            api_path("v2", "stage", "/broadcasts"),
+            "/v2/ns/stage/broadcasts".to_owned()
+        );
+        assert!(Namespace::is_valid("test-2_b"));
+        assert!(!Namespace::is_valid(&"a".repeat(65)));
+    }
+}
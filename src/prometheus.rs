@@ -0,0 +1,329 @@
+/// Prometheus exposition of metrics
+///
+/// When `prometheus_metrics` is enabled in the rocket Config, the metrics
+/// otherwise sent to statsd are also aggregated in memory and exposed by the
+/// `/__metrics__` endpoint in the Prometheus text format. Statsd may be used
+/// alongside it (when `statsd_host` is configured) or not at all.
+///
+/// Statsd metric names (prefixed by `statsd_label`) are converted to
+/// Prometheus names by replacing their dots with underscores. Counters gain a
+/// `_total` suffix and timers a `_milliseconds` suffix, timers and histograms
+/// becoming Prometheus histograms. Tags become labels.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use cadence::MetricSink;
+
+/// Upper bounds of the histogram buckets (in the unit of the recorded values,
+/// e.g. milliseconds for timers)
+const BUCKETS: [f64; 12] = [
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// Maximum number of label combinations recorded per metric, further
+/// combinations are dropped
+const MAX_SERIES: usize = 1000;
+
+/// Sorted label names and values
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative counts per BUCKETS (and a final +Inf bucket)
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug)]
+enum Family {
+    Counter(BTreeMap<Labels, f64>),
+    Gauge(BTreeMap<Labels, f64>),
+    Histogram(BTreeMap<Labels, Histogram>),
+}
+
+impl Family {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Family::Counter(_) => "counter",
+            Family::Gauge(_) => "gauge",
+            Family::Histogram(_) => "histogram",
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Family::Counter(series) | Family::Gauge(series) => series.len(),
+            Family::Histogram(series) => series.len(),
+        }
+    }
+}
+
+/// A parsed statsd metric line
+#[derive(Debug, PartialEq)]
+struct StatsdMetric<'a> {
+    name: &'a str,
+    value: f64,
+    type_: &'a str,
+    labels: Labels,
+}
+
+impl<'a> StatsdMetric<'a> {
+    /// Parse a metric (`name:value|type|#tag:value,...`) as emitted by cadence
+    fn parse(metric: &'a str) -> Option<StatsdMetric<'a>> {
+        let (name, rest) = metric.split_once(':')?;
+        let mut parts = rest.split('|');
+        let value = parts.next()?.parse::<f64>().ok()?;
+        let type_ = parts.next()?;
+        let mut labels: Labels = parts
+            .find_map(|part| part.strip_prefix('#'))
+            .map(|tags| {
+                tags.split(',')
+                    .filter_map(|tag| tag.split_once(':'))
+                    .map(|(key, value)| (sanitize_name(key), value.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        labels.sort();
+        labels.dedup_by(|a, b| a.0 == b.0);
+        Some(StatsdMetric {
+            name,
+            value,
+            type_,
+            labels,
+        })
+    }
+}
+
+/// Convert a statsd name into a valid Prometheus name
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_labels(out: &mut String, labels: &[(String, String)], le: Option<&str>) {
+    let le = le.map(|le| ("le", le.to_owned()));
+    let mut labels = labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), escape_label_value(value)))
+        .chain(le)
+        .peekable();
+    if labels.peek().is_none() {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}=\"{}\"", name, value);
+    }
+    out.push('}');
+}
+
+/// The in-memory aggregate of the metrics
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusRegistry {
+    families: Arc<Mutex<BTreeMap<String, Family>>>,
+}
+
+impl PrometheusRegistry {
+    /// Record a statsd metric line
+    fn record(&self, metric: &str) {
+        let metric = match StatsdMetric::parse(metric) {
+            Some(metric) => metric,
+            None => return,
+        };
+        let name = sanitize_name(metric.name);
+        let (name, empty) = match metric.type_ {
+            "c" => (format!("{}_total", name), Family::Counter(BTreeMap::new())),
+            "g" => (name, Family::Gauge(BTreeMap::new())),
+            "ms" => (
+                format!("{}_milliseconds", name),
+                Family::Histogram(BTreeMap::new()),
+            ),
+            "h" | "d" => (name, Family::Histogram(BTreeMap::new())),
+            _ => return,
+        };
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let family = families.entry(name).or_insert(empty);
+        if family.len() >= MAX_SERIES {
+            let exists = match family {
+                Family::Counter(series) | Family::Gauge(series) => {
+                    series.contains_key(&metric.labels)
+                }
+                Family::Histogram(series) => series.contains_key(&metric.labels),
+            };
+            if !exists {
+                return;
+            }
+        }
+        match family {
+            Family::Counter(series) => *series.entry(metric.labels).or_default() += metric.value,
+            Family::Gauge(series) => {
+                series.insert(metric.labels, metric.value);
+            }
+            Family::Histogram(series) => series
+                .entry(metric.labels)
+                .or_default()
+                .observe(metric.value),
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# TYPE {} {}", name, family.type_name());
+            match family {
+                Family::Counter(series) | Family::Gauge(series) => {
+                    for (labels, value) in series {
+                        out.push_str(name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", value);
+                    }
+                }
+                Family::Histogram(series) => {
+                    for (labels, histogram) in series {
+                        let mut cumulative = 0;
+                        for (i, count) in histogram.buckets.iter().enumerate() {
+                            cumulative += count;
+                            let le = BUCKETS
+                                .get(i)
+                                .map_or_else(|| "+Inf".to_owned(), |bound| bound.to_string());
+                            let _ = write!(out, "{}_bucket", name);
+                            write_labels(&mut out, labels, Some(&le));
+                            let _ = writeln!(out, " {}", cumulative);
+                        }
+                        let _ = write!(out, "{}_sum", name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", histogram.sum);
+                        let _ = write!(out, "{}_count", name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", histogram.count);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A cadence MetricSink recording into a PrometheusRegistry
+pub struct PrometheusMetricSink {
+    registry: PrometheusRegistry,
+}
+
+impl PrometheusMetricSink {
+    pub fn new(registry: PrometheusRegistry) -> PrometheusMetricSink {
+        PrometheusMetricSink { registry }
+    }
+}
+
+impl MetricSink for PrometheusMetricSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.registry.record(metric);
+        Ok(metric.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cadence::{CountedExt, Gauged, StatsdClient, Timed};
+
+    use super::{PrometheusMetricSink, PrometheusRegistry, StatsdMetric};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            StatsdMetric::parse("megaphone.broadcast.cmd.update:1|c|#version:v1,broadcaster:foo"),
+            Some(StatsdMetric {
+                name: "megaphone.broadcast.cmd.update",
+                value: 1.0,
+                type_: "c",
+                labels: vec![
+                    ("broadcaster".to_owned(), "foo".to_owned()),
+                    ("version".to_owned(), "v1".to_owned())
+                ],
+            })
+        );
+        assert!(StatsdMetric::parse("megaphone.timer:12|ms")
+            .unwrap()
+            .labels
+            .is_empty());
+        assert_eq!(StatsdMetric::parse("garbage"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let registry = PrometheusRegistry::default();
+        let client =
+            StatsdClient::from_sink("megaphone", PrometheusMetricSink::new(registry.clone()));
+        client
+            .incr_with_tags("broadcast.cmd.update")
+            .with_tag("broadcaster", "foo")
+            .send();
+        client
+            .incr_with_tags("broadcast.cmd.update")
+            .with_tag("broadcaster", "foo")
+            .send();
+        client.gauge("db.pool.connections", 3u64).unwrap();
+        client.time("broadcast.update", 7u64).unwrap();
+        client
+            .incr_with_tags("broadcast.cmd.dump")
+            .with_tag("quoted", "a\"b")
+            .send();
+
+        let rendered = registry.render();
+        let expected = [
+            "# TYPE megaphone_broadcast_cmd_update_total counter",
+            "megaphone_broadcast_cmd_update_total{broadcaster=\"foo\"} 2",
+            "# TYPE megaphone_broadcast_cmd_dump_total counter",
+            "megaphone_broadcast_cmd_dump_total{quoted=\"a\\\"b\"} 1",
+            "# TYPE megaphone_db_pool_connections gauge",
+            "megaphone_db_pool_connections 3",
+            "# TYPE megaphone_broadcast_update_milliseconds histogram",
+            "megaphone_broadcast_update_milliseconds_bucket{le=\"5\"} 0",
+            "megaphone_broadcast_update_milliseconds_bucket{le=\"10\"} 1",
+            "megaphone_broadcast_update_milliseconds_bucket{le=\"+Inf\"} 1",
+            "megaphone_broadcast_update_milliseconds_sum 7",
+            "megaphone_broadcast_update_milliseconds_count 1",
+        ];
+        for line in expected.iter() {
+            assert!(
+                rendered.lines().any(|l| l == *line),
+                "missing {:?} in:\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+/// Error reporting to Sentry
+///
+/// Sentry is enabled by the `sentry_dsn` rocket Config (or the `SENTRY_DSN`
+/// environment variable). Error responses are reported when their status
+/// matches the `sentry_report_statuses` (status classes like `5xx` or
+/// statuses like `404`, default `["5xx"]`) or their errno is listed in
+/// `sentry_report_errnos`, e.g.
+///
+/// ```toml
+/// [production]
+/// sentry_report_statuses = ["5xx", "401"]
+/// sentry_report_errnos = [105]
+/// sentry_sample_rate = 0.5
+/// ```
+///
+/// Only `sentry_sample_rate` (default `1.0`) of the events are sent. Events
+/// carry the `sentry_environment` (default the rocket environment) and the
+/// release of `version.json`, along with the request and its authenticated
+/// user. Credentials (the `Authorization` header, bearer tokens and the like)
+/// are scrubbed from every event.
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use rocket::{config::ConfigError, http::Status, Config, Request};
+use sentry::protocol::{Event, Map, Request as SentryRequest, User, Value};
+use serde::Deserialize;
+
+use crate::auth::AuthenticatedUser;
+use crate::error::{HandlerError, HandlerResult};
+use crate::request_id::RequestId;
+
+/// Replaces the value of scrubbed data
+const FILTERED: &str = "[Filtered]";
+
+/// Default `sentry_report_statuses`
+const DEFAULT_REPORT_STATUSES: [&str; 1] = ["5xx"];
+
+/// Headers whose values are always scrubbed
+const SENSITIVE_HEADERS: [&str; 5] = [
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-api-key",
+];
+
+lazy_static! {
+    /// Credentials embedded in text (e.g. error messages): authorization
+    /// schemes followed by credentials or credentials assigned to names
+    static ref CREDENTIALS_RE: Regex = Regex::new(
+        r#"(?i)(\b(?:bearer|basic)\s+|\w*(?:token|secret|password|key)\s*[=:]\s*)[^\s&,;"']+"#
+    )
+    .unwrap();
+    /// Names of query parameters or fields holding credentials
+    static ref SENSITIVE_NAME_RE: Regex =
+        Regex::new(r"(?i)auth|token|secret|password|passwd|key|signature|cookie").unwrap();
+}
+
+#[derive(Deserialize)]
+struct VersionInfo {
+    version: String,
+}
+
+/// Return the release of `version.json`
+fn release() -> Option<String> {
+    let info: VersionInfo = serde_json::from_str(include_str!("../version.json")).ok()?;
+    if info.version.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", env!("CARGO_PKG_NAME"), info.version))
+}
+
+/// Return the Sentry client (when configured)
+pub fn init_sentry(config: &Config) -> HandlerResult<Option<sentry::ClientInitGuard>> {
+    let sample_rate = match config.get_float("sentry_sample_rate") {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => rate as f32,
+        Err(ConfigError::Missing(_)) => 1.0,
+        _ => Err(HandlerError::internal(
+            "Invalid ROCKET_SENTRY_SAMPLE_RATE (must be between 0 and 1)".to_owned(),
+        ))?,
+    };
+    let environment = match config.get_string("sentry_environment") {
+        Ok(environment) => environment,
+        Err(ConfigError::Missing(_)) => config.environment.to_string(),
+        Err(e) => Err(HandlerError::internal(format!(
+            "Invalid ROCKET_SENTRY_ENVIRONMENT: {}",
+            e
+        )))?,
+    };
+    let opts = sentry::ClientOptions {
+        // debug: true,
+        sample_rate,
+        environment: Some(Cow::Owned(environment)),
+        release: release().map(Cow::Owned),
+        before_send: Some(Arc::new(|event| Some(scrub_event(event)))),
+        ..Default::default()
+    };
+    Ok(if let Ok(sentry_dsn) = config.get_string("sentry_dsn") {
+        Some(sentry::init((sentry_dsn, opts)))
+    } else {
+        // Check the global env to see if we need to connect to sentry.
+        if env::var("SENTRY_DSN").is_ok() {
+            Some(sentry::init(opts))
+        } else {
+            None
+        }
+    })
+}
+
+/// A status (or status class) of reported errors
+#[derive(Debug, PartialEq)]
+enum StatusMatch {
+    /// e.g. 5 for `5xx`
+    Class(u16),
+    Code(u16),
+}
+
+impl StatusMatch {
+    fn parse(status: &str) -> Option<StatusMatch> {
+        let bytes = status.as_bytes();
+        match bytes {
+            [class @ b'1'..=b'5', b'x', b'x'] => Some(StatusMatch::Class(u16::from(class - b'0'))),
+            _ => status
+                .parse()
+                .ok()
+                .filter(|code| (100..600).contains(code))
+                .map(StatusMatch::Code),
+        }
+    }
+
+    fn matches(&self, status: Status) -> bool {
+        match self {
+            StatusMatch::Class(class) => status.code / 100 == *class,
+            StatusMatch::Code(code) => status.code == *code,
+        }
+    }
+}
+
+/// Which error responses are reported to Sentry
+#[derive(Debug)]
+pub struct ReportPolicy {
+    statuses: Vec<StatusMatch>,
+    errnos: HashSet<i32>,
+}
+
+impl Default for ReportPolicy {
+    fn default() -> ReportPolicy {
+        ReportPolicy {
+            statuses: DEFAULT_REPORT_STATUSES
+                .iter()
+                .filter_map(|status| StatusMatch::parse(status))
+                .collect(),
+            errnos: HashSet::new(),
+        }
+    }
+}
+
+impl ReportPolicy {
+    pub fn from_config(config: &Config) -> HandlerResult<ReportPolicy> {
+        let mut policy = ReportPolicy::default();
+        match config.get_slice("sentry_report_statuses") {
+            Ok(statuses) => {
+                policy.statuses = statuses
+                    .iter()
+                    .map(|status| status.as_str().and_then(StatusMatch::parse))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| {
+                        HandlerError::internal(
+                        "Invalid ROCKET_SENTRY_REPORT_STATUSES (expected e.g. \"5xx\" or \"404\")"
+                            .to_owned(),
+                    )
+                    })?
+            }
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_SENTRY_REPORT_STATUSES: {}",
+                e
+            )))?,
+        }
+        match config.get_slice("sentry_report_errnos") {
+            Ok(errnos) => {
+                policy.errnos = errnos
+                    .iter()
+                    .map(|errno| errno.as_integer().map(|errno| errno as i32))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| {
+                        HandlerError::internal("Invalid ROCKET_SENTRY_REPORT_ERRNOS".to_owned())
+                    })?
+            }
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_SENTRY_REPORT_ERRNOS: {}",
+                e
+            )))?,
+        }
+        Ok(policy)
+    }
+
+    /// Whether an error response is reported
+    pub fn reports(&self, status: Status, errno: i32) -> bool {
+        self.errnos.contains(&errno) || self.statuses.iter().any(|m| m.matches(status))
+    }
+}
+
+/// Attach the request (and its authenticated user) to an event
+pub fn add_request_context(request: &Request<'_>, event: &mut Event<'static>) {
+    let headers = request.headers();
+    let url = headers.get_one("Host").and_then(|host| {
+        format!("http://{}{}", host, request.uri().path())
+            .parse()
+            .ok()
+    });
+    event.request = Some(SentryRequest {
+        url,
+        method: Some(request.method().as_str().to_owned()),
+        query_string: request.uri().query().map(str::to_owned),
+        headers: headers
+            .iter()
+            .map(|header| (header.name().to_owned(), header.value().to_owned()))
+            .collect(),
+        ..Default::default()
+    });
+    if let Some((id, group)) = AuthenticatedUser::from_request(request).0 {
+        let mut other = Map::new();
+        other.insert("group".to_owned(), Value::from(group.name()));
+        event.user = Some(User {
+            id: Some(id),
+            other,
+            ..Default::default()
+        });
+    }
+    event
+        .tags
+        .insert("request_id".to_owned(), RequestId::from_request(request).0);
+}
+
+/// Scrub credentials from text
+fn scrub_text(text: &str) -> Cow<'_, str> {
+    CREDENTIALS_RE.replace_all(text, |caps: &Captures<'_>| {
+        format!("{}{}", &caps[1], FILTERED)
+    })
+}
+
+/// Scrub the values of sensitive parameters from a query string
+fn scrub_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SENSITIVE_NAME_RE.is_match(name) => format!("{}={}", name, FILTERED),
+            _ => scrub_text(param).into_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn scrub_value(name: &str, value: &mut Value) {
+    match value {
+        Value::String(_) if SENSITIVE_NAME_RE.is_match(name) => {
+            *value = Value::from(FILTERED);
+        }
+        Value::String(s) => {
+            if let Cow::Owned(scrubbed) = scrub_text(s) {
+                *s = scrubbed;
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| scrub_value(name, v)),
+        Value::Object(map) => map.iter_mut().for_each(|(k, v)| scrub_value(k, v)),
+        _ => (),
+    }
+}
+
+fn scrub_string(s: &mut String) {
+    if let Cow::Owned(scrubbed) = scrub_text(s) {
+        *s = scrubbed;
+    }
+}
+
+/// Scrub credentials from an event
+pub fn scrub_event(mut event: Event<'static>) -> Event<'static> {
+    if let Some(request) = event.request.as_mut() {
+        for (name, value) in request.headers.iter_mut() {
+            if SENSITIVE_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                *value = FILTERED.to_owned();
+            } else {
+                scrub_string(value);
+            }
+        }
+        if let Some(query) = request.query_string.as_mut() {
+            *query = scrub_query(query);
+        }
+        if let Some(url) = request.url.as_mut() {
+            if let Some(query) = url.query().map(scrub_query) {
+                url.set_query(Some(&query));
+            }
+        }
+        if request.cookies.is_some() {
+            request.cookies = Some(FILTERED.to_owned());
+        }
+        if let Some(data) = request.data.as_mut() {
+            scrub_string(data);
+        }
+    }
+    if let Some(message) = event.message.as_mut() {
+        scrub_string(message);
+    }
+    if let Some(logentry) = event.logentry.as_mut() {
+        scrub_string(&mut logentry.message);
+    }
+    for exception in event.exception.values.iter_mut() {
+        if let Some(value) = exception.value.as_mut() {
+            scrub_string(value);
+        }
+    }
+    for breadcrumb in event.breadcrumbs.values.iter_mut() {
+        if let Some(message) = breadcrumb.message.as_mut() {
+            scrub_string(message);
+        }
+        breadcrumb
+            .data
+            .iter_mut()
+            .for_each(|(name, value)| scrub_value(name, value));
+    }
+    event
+        .extra
+        .iter_mut()
+        .for_each(|(name, value)| scrub_value(name, value));
+    event.tags.iter_mut().for_each(|(name, value)| {
+        if SENSITIVE_NAME_RE.is_match(name) {
+            *value = FILTERED.to_owned();
+        } else {
+            scrub_string(value);
+        }
+    });
+    event
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::Status;
+    use sentry::protocol::{Event, Exception, Request as SentryRequest};
+
+    use super::{release, scrub_event, scrub_text, ReportPolicy, FILTERED};
+
+    #[test]
+    fn test_policy() {
+        let config = Config::build(Environment::Development).unwrap();
+        let policy = ReportPolicy::from_config(&config).unwrap();
+        assert!(policy.reports(Status::ServiceUnavailable, 202));
+        assert!(!policy.reports(Status::NotFound, 123));
+        assert!(!policy.reports(Status::BadRequest, 105));
+
+        let config = Config::build(Environment::Development)
+            .extra("sentry_report_statuses", vec!["5xx", "404"])
+            .extra("sentry_report_errnos", vec![105])
+            .unwrap();
+        let policy = ReportPolicy::from_config(&config).unwrap();
+        assert!(policy.reports(Status::InternalServerError, 201));
+        assert!(policy.reports(Status::NotFound, 123));
+        assert!(policy.reports(Status::BadRequest, 105));
+        assert!(!policy.reports(Status::BadRequest, 104));
+
+        for statuses in [vec!["6xx"], vec!["nope"], vec!["42"]] {
+            let config = Config::build(Environment::Development)
+                .extra("sentry_report_statuses", statuses)
+                .unwrap();
+            assert!(ReportPolicy::from_config(&config).is_err());
+        }
+        let config = Config::build(Environment::Development)
+            .extra("sentry_report_errnos", vec![Value::from("105")])
+            .unwrap();
+        assert!(ReportPolicy::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_release() {
+        assert_eq!(release().as_deref(), Some("megaphone@devel"));
+    }
+
+    #[test]
+    fn test_scrub() {
+        assert_eq!(
+            scrub_text("Invalid header: Bearer feedfacedeadbeef"),
+            "Invalid header: Bearer [Filtered]"
+        );
+        assert_eq!(
+            scrub_text("token=abc, secret: xyz"),
+            "token=[Filtered], secret: [Filtered]"
+        );
+        assert_eq!(scrub_text("A database error"), "A database error");
+
+        let mut event = Event {
+            message: Some("Failed with Bearer feedfacedeadbeef".to_owned()),
+            request: Some(SentryRequest {
+                url: "http://localhost/v1/broadcasts?token=t0k3n&limit=1"
+                    .parse()
+                    .ok(),
+                query_string: Some("token=t0k3n&limit=1".to_owned()),
+                headers: vec![
+                    (
+                        "Authorization".to_owned(),
+                        "Bearer feedfacedeadbeef".to_owned(),
+                    ),
+                    ("Accept".to_owned(), "application/json".to_owned()),
+                ]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        event.exception.values.push(Exception {
+            ty: "HandlerError".to_owned(),
+            value: Some("Invalid authorization header: Bearer feedfacedeadbeef".to_owned()),
+            ..Default::default()
+        });
+        event
+            .extra
+            .insert("webhook_secret".to_owned(), "s3cr3t".into());
+        let event = scrub_event(event);
+        let serialized = serde_json::to_string(&event).unwrap();
+        assert!(!serialized.contains("feedfacedeadbeef"));
+        assert!(!serialized.contains("t0k3n"));
+        assert!(!serialized.contains("s3cr3t"));
+        let request = event.request.unwrap();
+        assert_eq!(request.headers["Authorization"], FILTERED);
+        assert_eq!(request.headers["Accept"], "application/json");
+        assert_eq!(
+            request.query_string.as_deref(),
+            Some("token=[Filtered]&limit=1")
+        );
+    }
+}
@@ -0,0 +1,103 @@
+/// Request ids correlating responses, logs and errors
+///
+/// Each request takes the id of its `X-Request-Id` header, or a generated
+/// one when it's missing or invalid, echoed in the response's `X-Request-Id`
+/// header. The id is included in the request's log records, its Sentry
+/// events and its JSON error responses.
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    request::{self, FromRequest},
+    Data, Outcome, Request, Response,
+};
+use uuid::Uuid;
+
+use crate::error::HandlerError;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Maximum length of an incoming request id
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Return a new random request id
+    fn generate() -> RequestId {
+        RequestId(Uuid::new_v4().simple().to_string())
+    }
+
+    /// Whether `id` is an acceptable incoming request id (printable ASCII
+    /// without spaces)
+    fn is_valid(id: &str) -> bool {
+        !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+    }
+
+    /// Return the id of the request
+    pub fn from_request(request: &Request<'_>) -> RequestId {
+        request
+            .local_cache(|| {
+                request
+                    .headers()
+                    .get_one(REQUEST_ID_HEADER)
+                    .filter(|id| RequestId::is_valid(id))
+                    .map(|id| RequestId(id.to_owned()))
+                    .unwrap_or_else(RequestId::generate)
+            })
+            .clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestId {
+    type Error = HandlerError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, HandlerError> {
+        Outcome::Success(RequestId::from_request(request))
+    }
+}
+
+/// Assigns each request its RequestId, echoing it in the response
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        RequestId::from_request(request);
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        response.set_header(Header::new(
+            REQUEST_ID_HEADER,
+            RequestId::from_request(request).0,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RequestId;
+
+    #[test]
+    fn test_request_id() {
+        assert!(RequestId::is_valid("d1e8a5c7-5c3a-4b5e-9d8f-0a1b2c3d4e5f"));
+        assert!(RequestId::is_valid(
+            "Root=1-67891233-abcdef012345678912345678"
+        ));
+        assert!(!RequestId::is_valid(""));
+        assert!(!RequestId::is_valid("two words"));
+        assert!(!RequestId::is_valid("line\nbreak"));
+        assert!(!RequestId::is_valid(&"a".repeat(129)));
+        let generated = RequestId::generate();
+        assert_eq!(generated.0.len(), 32);
+        assert!(RequestId::is_valid(&generated.0));
+        assert_ne!(generated, RequestId::generate());
+    }
+}
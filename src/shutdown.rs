@@ -0,0 +1,57 @@
+/// Stopping of background threads along with the Rocket
+///
+/// Background threads sleep between runs on a Listener of the Shutdown
+/// managed by the Rocket. Dropping the Shutdown (along with the Rocket)
+/// wakes them, so they stop rather than outliving it.
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    senders: Mutex<Vec<Sender<()>>>,
+}
+
+impl Shutdown {
+    /// Return a Listener for a background thread
+    pub fn listener(&self) -> Listener {
+        let (sender, receiver) = mpsc::channel();
+        self.senders
+            .lock()
+            .expect("Shutdown senders poisoned")
+            .push(sender);
+        Listener(receiver)
+    }
+}
+
+pub struct Listener(Receiver<()>);
+
+impl Listener {
+    /// Sleep for `interval`
+    ///
+    /// Returns false (immediately) once shut down.
+    pub fn sleep(&self, interval: Duration) -> bool {
+        match self.0.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => true,
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Shutdown;
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::default();
+        let listener = shutdown.listener();
+        assert!(listener.sleep(Duration::from_millis(1)));
+        drop(shutdown);
+        let start = Instant::now();
+        assert!(!listener.sleep(Duration::from_secs(60)));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+}
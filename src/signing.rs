@@ -0,0 +1,184 @@
+/// Signing of broadcast dumps
+///
+/// When `signing_key_path` in the rocket Config names a PKCS#8 PEM encoded
+/// Ed25519 private key, broadcast dumps (and single broadcasts) are signed so
+/// that consumers re-serving them (e.g. autopush) can prove they originated
+/// from megaphone.
+/// The `Megaphone-Signature` response header carries the (unpadded base64url
+/// encoded) Ed25519 signature of the response body and `Megaphone-Key-Id` the
+/// id of the signing key: `signing_key_id` from the rocket Config, defaulting
+/// to a prefix of the public key.
+use std::fs;
+use std::io::Cursor;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{
+    pkcs8::{spki::der::pem::LineEnding, DecodePrivateKey, EncodePublicKey},
+    Signer as _, SigningKey,
+};
+use rocket::{
+    config::ConfigError,
+    http::Header,
+    response::{self, Responder},
+    Config, Request, State,
+};
+
+use crate::error::{HandlerError, HandlerResult};
+
+pub const SIGNATURE_HEADER: &str = "Megaphone-Signature";
+pub const KEY_ID_HEADER: &str = "Megaphone-Key-Id";
+
+/// Length of the public key prefix forming the default key id
+const DEFAULT_KEY_ID_LEN: usize = 8;
+
+pub struct Signer {
+    key: SigningKey,
+    key_id: String,
+}
+
+impl Signer {
+    /// Return a Signer, or None if signing is disabled
+    pub fn from_config(config: &Config) -> HandlerResult<Option<Signer>> {
+        let path = match config.get_str("signing_key_path") {
+            Ok(path) => path,
+            Err(ConfigError::Missing(_)) => return Ok(None),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_SIGNING_KEY_PATH: {}",
+                e
+            )))?,
+        };
+        let pem = fs::read_to_string(path).map_err(|e| {
+            HandlerError::internal(format!("Could not read signing key {:?}: {}", path, e))
+        })?;
+        let key = SigningKey::from_pkcs8_pem(&pem).map_err(|e| {
+            HandlerError::internal(format!("Invalid Ed25519 signing key {:?}: {}", path, e))
+        })?;
+        let key_id = match config.get_string("signing_key_id") {
+            Ok(key_id) => key_id,
+            Err(ConfigError::Missing(_)) => {
+                URL_SAFE_NO_PAD.encode(&key.verifying_key().as_bytes()[..DEFAULT_KEY_ID_LEN])
+            }
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_SIGNING_KEY_ID: {}",
+                e
+            )))?,
+        };
+        Ok(Some(Signer { key, key_id }))
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Return the unpadded base64url encoded signature of a message
+    pub fn sign(&self, message: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(self.key.sign(message).to_bytes())
+    }
+
+    /// Return the unpadded base64url encoded raw public key
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Return the PEM encoded (SubjectPublicKeyInfo) public key
+    pub fn public_key_pem(&self) -> HandlerResult<String> {
+        self.key
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| HandlerError::internal(format!("Could not encode public key: {}", e)))
+    }
+}
+
+/// A Responder whose body is signed by the managed Signer (if any)
+pub struct Signed<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for Signed<R> {
+    fn respond_to(self, request: &Request<'_>) -> response::Result<'r> {
+        let mut response = self.0.respond_to(request)?;
+        let signer = request.guard::<State<'_, Option<Signer>>>().succeeded();
+        if let Some(signer) = signer.as_ref().and_then(|signer| signer.inner().as_ref()) {
+            let body = response.body_bytes().unwrap_or_default();
+            response.set_header(Header::new(SIGNATURE_HEADER, signer.sign(&body)));
+            response.set_header(Header::new(KEY_ID_HEADER, signer.key_id.clone()));
+            response.set_sized_body(Cursor::new(body));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ed25519_dalek::{
+        pkcs8::{spki::der::pem::LineEnding, DecodePublicKey, EncodePrivateKey},
+        Signature, SigningKey, Verifier, VerifyingKey,
+    };
+    use rocket::config::{Config, Environment};
+
+    use super::Signer;
+
+    /// Write a test signing key to a temporary file, returning its path
+    pub(crate) fn signing_key_file(name: &str) -> PathBuf {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let path = std::env::temp_dir().join(format!("megaphone-{}.pem", name));
+        fs::write(&path, key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+        path
+    }
+
+    /// Verify a base64url encoded signature against a base64url encoded
+    /// public key
+    pub(crate) fn verify(public_key: &str, message: &[u8], signature: &str) -> bool {
+        let public_key: [u8; 32] = URL_SAFE_NO_PAD
+            .decode(public_key)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature: [u8; 64] = URL_SAFE_NO_PAD
+            .decode(signature)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        VerifyingKey::from_bytes(&public_key)
+            .unwrap()
+            .verify(message, &Signature::from_bytes(&signature))
+            .is_ok()
+    }
+
+    #[test]
+    fn test_sign() {
+        let path = signing_key_file("test_sign");
+        let config = Config::build(Environment::Development)
+            .extra("signing_key_path", path.to_str().unwrap())
+            .unwrap();
+        let signer = Signer::from_config(&config).unwrap().unwrap();
+        assert_eq!(signer.key_id().len(), 11);
+        let signature = signer.sign(b"{}");
+        assert!(verify(&signer.public_key(), b"{}", &signature));
+        assert!(!verify(&signer.public_key(), b"{ }", &signature));
+        let pem = signer.public_key_pem().unwrap();
+        assert_eq!(
+            VerifyingKey::from_public_key_pem(&pem).unwrap().as_bytes(),
+            &URL_SAFE_NO_PAD.decode(signer.public_key()).unwrap()[..]
+        );
+
+        let config = Config::build(Environment::Development)
+            .extra("signing_key_path", path.to_str().unwrap())
+            .extra("signing_key_id", "2026-10")
+            .unwrap();
+        let signer = Signer::from_config(&config).unwrap().unwrap();
+        assert_eq!(signer.key_id(), "2026-10");
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config::build(Environment::Development).unwrap();
+        assert!(Signer::from_config(&config).unwrap().is_none());
+        let config = Config::build(Environment::Development)
+            .extra("signing_key_path", "/nonexistent/megaphone.pem")
+            .unwrap();
+        assert!(Signer::from_config(&config).is_err());
+    }
+}
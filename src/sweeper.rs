@@ -0,0 +1,195 @@
+/// Background maintenance of broadcasts
+///
+/// Periodically promotes PendingBroadcasts into broadcastsv1 once they're
+/// due (dropping those now failing their broadcaster's VersionPolicy) and
+/// expires Broadcasts past their `expires_at`. Runs every
+/// `sweep_interval` seconds from the rocket Config (0 disables it).
+///
+/// Expired Broadcasts (already hidden from readers) are removed unless they
+/// have a fallback version in the `expiry_fallbacks` table from the rocket
+/// Config (keyed by Broadcast id, applying to every namespace), e.g.
+///
+/// ```toml
+/// [development.expiry_fallbacks]
+/// "shield/experiment1" = "____NOP____"
+/// ```
+///
+/// BroadcastEvents older than `event_retention` seconds from the rocket
+/// Config (default 7 days, 0 retains them indefinitely) are pruned once
+/// acknowledged by every consumer of their namespace.
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rocket::{config::ConfigError, Config};
+use slog::{error, info, warn, Logger};
+
+use crate::db::{
+    models::{Broadcast, BroadcastEvent, Expiration, PendingBroadcast, Promotion},
+    MysqlPool,
+};
+use crate::error::{HandlerError, HandlerResult};
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+use crate::tags::Tags;
+use crate::version_policy::VersionPolicies;
+use crate::webhooks::Webhooks;
+
+/// Default number of seconds between sweeps
+const DEFAULT_SWEEP_INTERVAL: i64 = 5;
+
+/// Default number of seconds to retain BroadcastEvents
+const DEFAULT_EVENT_RETENTION: i64 = 7 * 24 * 60 * 60;
+
+pub struct Sweeper {
+    pool: MysqlPool,
+    log: Logger,
+    metrics: Metrics,
+    interval: Duration,
+    fallbacks: HashMap<String, String>,
+    policies: VersionPolicies,
+    webhooks: Webhooks,
+    event_retention: Option<chrono::Duration>,
+}
+
+/// Load the expired Broadcasts' fallback versions
+fn fallbacks_from_config(config: &Config) -> HandlerResult<HashMap<String, String>> {
+    let table = match config.get_table("expiry_fallbacks") {
+        Ok(table) => table,
+        Err(ConfigError::Missing(_)) => return Ok(HashMap::new()),
+        Err(e) => Err(HandlerError::internal(format!(
+            "Invalid ROCKET_EXPIRY_FALLBACKS: {}",
+            e
+        )))?,
+    };
+    table
+        .iter()
+        .map(|(id, version)| {
+            let version = version.as_str().ok_or_else(|| {
+                HandlerError::internal(format!("Invalid expiry_fallbacks version for: {:?}", id))
+            })?;
+            Ok((id.to_owned(), version.to_owned()))
+        })
+        .collect()
+}
+
+impl Sweeper {
+    /// Return a Sweeper, or None if sweeping is disabled
+    pub fn from_config(
+        config: &Config,
+        pool: MysqlPool,
+        log: Logger,
+        metrics: Metrics,
+        webhooks: Webhooks,
+    ) -> HandlerResult<Option<Sweeper>> {
+        let interval = match config.get_int("sweep_interval") {
+            Ok(interval) if interval >= 0 => interval,
+            Err(ConfigError::Missing(_)) => DEFAULT_SWEEP_INTERVAL,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_SWEEP_INTERVAL".to_owned(),
+            ))?,
+        };
+        if interval == 0 {
+            return Ok(None);
+        }
+        let event_retention = match config.get_int("event_retention") {
+            Ok(retention) if retention >= 0 => retention,
+            Err(ConfigError::Missing(_)) => DEFAULT_EVENT_RETENTION,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_EVENT_RETENTION".to_owned(),
+            ))?,
+        };
+        Ok(Some(Sweeper {
+            pool,
+            log,
+            metrics,
+            interval: Duration::from_secs(interval as u64),
+            fallbacks: fallbacks_from_config(config)?,
+            policies: VersionPolicies::from_config(config)?,
+            webhooks,
+            event_retention: Some(event_retention)
+                .filter(|retention| *retention > 0)
+                .map(chrono::Duration::seconds),
+        }))
+    }
+
+    /// Run the Sweeper in a background thread until `shutdown`
+    pub fn spawn(self, shutdown: &Shutdown) -> HandlerResult<thread::JoinHandle<()>> {
+        let listener = shutdown.listener();
+        thread::Builder::new()
+            .name("sweeper".to_owned())
+            .spawn(move || {
+                while listener.sleep(self.interval) {
+                    if let Err(e) = self.sweep() {
+                        error!(self.log, "Sweep failed: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| HandlerError::internal(format!("Could not start sweeper: {:?}", e)))
+    }
+
+    pub fn sweep(&self) -> HandlerResult<()> {
+        let conn = self.pool.get()?;
+        let start = Instant::now();
+        for (pending, promotion) in
+            PendingBroadcast::promote_due(&conn, &self.policies, &self.webhooks)?
+        {
+            match promotion {
+                Promotion::Promoted => {
+                    info!(
+                        self.log,
+                        "Promoted pending broadcast: {} version: {}",
+                        pending.id(),
+                        &pending.version;
+                        "pending_id" => pending.id
+                    );
+                    self.metrics.incr("broadcast.promoted");
+                }
+                Promotion::Rejected(e) => {
+                    warn!(
+                        self.log,
+                        "Dropped pending broadcast: {} version: {}: {}",
+                        pending.id(),
+                        &pending.version,
+                        e;
+                        "pending_id" => pending.id
+                    );
+                    self.metrics.incr("broadcast.promotion_rejected");
+                }
+            }
+        }
+        for (bcast, expiration) in Broadcast::expire_due(&conn, &self.fallbacks, &self.webhooks)? {
+            let action = match &expiration {
+                Expiration::Removed => {
+                    info!(self.log, "Expired broadcast: {} removed", bcast.id());
+                    "removed"
+                }
+                Expiration::Reverted(fallback) => {
+                    info!(
+                        self.log,
+                        "Expired broadcast: {} reverted to: {}",
+                        bcast.id(),
+                        fallback
+                    );
+                    "reverted"
+                }
+            };
+            let mut tags = Tags::default();
+            tags.tags.insert("action".to_owned(), action.to_owned());
+            self.metrics.incr_with_tags("broadcast.expired", Some(tags));
+        }
+        if let Some(retention) = self.event_retention {
+            let pruned = BroadcastEvent::prune(&conn, Utc::now().naive_utc() - retention)?;
+            if pruned > 0 {
+                info!(self.log, "Pruned {} broadcast events", pruned);
+            }
+        }
+        self.metrics.timer_with_tags(
+            "broadcast.sweep",
+            (Instant::now() - start).as_millis() as u64,
+            None,
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,30 @@
+/// Metric tags
+///
+/// Static tags from the `metric_tags` table in the rocket Config are added to
+/// every metric, e.g.
+///
+/// ```toml
+/// [development.metric_tags]
+/// env = "stage"
+/// ```
+///
+/// The remaining (per metric) tags are subject to a TagPolicy limiting their
+/// cardinality: `metric_tag_allowlist` lists the tag names emitted (by
+/// default all of them), and the `metric_tag_buckets` table buckets the values of tags, either hashing them
+/// into a number of buckets or keeping a list of values (others becoming
+/// `other`). The FIXED_TAGS, of a small fixed set of values (e.g. the
+/// `method` and `status` of request metrics), are always emitted, e.g.
+///
+/// ```toml
+/// [development]
+/// metric_tag_allowlist = ["namespace", "broadcaster", "channel_id"]
+///
+/// [development.metric_tag_buckets]
+/// channel_id = 16
+/// broadcaster = ["remote-settings", "shield"]
+/// ```
 use rocket::{
+    config::{ConfigError, Value},
     request::{self, FromRequest},
     Config, Outcome, Request, State,
 };
@@ -6,9 +32,19 @@ use serde::{
     ser::{SerializeMap, Serializer},
     Serialize,
 };
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::error::{HandlerError, HandlerResult};
+use crate::user_agent::user_agent_tags;
+
+/// Tags emitted regardless of `metric_tag_allowlist`, their values being of a
+/// small fixed set
+const FIXED_TAGS: [&str; 7] = [
+    "action", "encoding", "errno", "method", "outcome", "route", "status",
+];
+
+/// Bucket of the values missing from a `metric_tag_buckets` list
+const OTHER_BUCKET: &str = "other";
 
 #[derive(Clone, Debug, Default)]
 pub struct Tags {
@@ -32,14 +68,12 @@ impl Serialize for Tags {
 }
 
 // Tags are extra data to be recorded in metric and logging calls.
-// If additional tags are required or desired, you will need to add them to the
-// mutable extensions, e.g.
+// The Tags request guard returns the static tags along with the request's own
+// tags (currently those of its User-Agent), e.g.
 // ```
-//      let mut tags = request.extensions_mut().get::<Tags>();
-//      tags.insert("SomeLabel".to_owned(), "whatever".to_owned());
+//      let mut tags = Tags::from_request(request);
+//      tags.tags.insert("SomeLabel".to_owned(), "whatever".to_owned());
 // ```
-// how you get the request (or the response, and it's set of `extensions`) to whatever
-// function requires it, is left as an exercise for the reader.
 impl Tags {
     /*
     pub fn with_tags(tags: HashMap<String, String>) -> Tags {
@@ -87,33 +121,151 @@ impl<'a, 'r> FromRequest<'a, 'r> for Tags {
     type Error = HandlerError;
 
     fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        Outcome::Success(req.guard::<State<Tags>>().unwrap().inner().clone())
+        Outcome::Success(Tags::from_request(req))
     }
 }
 
 impl Tags {
-    pub fn init(_config: &Config) -> HandlerResult<Self> {
-        let tags = HashMap::new();
+    /// Return the static tags
+    pub fn init(config: &Config) -> HandlerResult<Self> {
+        let mut tags = HashMap::new();
         let extra = HashMap::new();
-        /* parse the header?
-        if let Some(ua) = req.headers().get("User-Agent") {
-            if let Ok(uas) = ua.to_str() {
-                let (ua_result, metrics_os, metrics_browser) = parse_user_agent(uas);
-                insert_if_not_empty("ua.os.family", metrics_os, &mut tags);
-                insert_if_not_empty("ua.browser.family", metrics_browser, &mut tags);
-                insert_if_not_empty("ua.name", ua_result.name, &mut tags);
-                insert_if_not_empty("ua.os.ver", &ua_result.os_version.to_owned(), &mut tags);
-                insert_if_not_empty("ua.browser.ver", ua_result.version, &mut tags);
-                extra.insert("ua".to_owned(), uas.to_string());
+        match config.get_table("metric_tags") {
+            Ok(table) => {
+                for (name, value) in table {
+                    let value = value.as_str().ok_or_else(|| {
+                        HandlerError::internal(format!("Invalid metric_tags value for: {:?}", name))
+                    })?;
+                    tags.insert(name.to_owned(), value.to_owned());
+                }
             }
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_METRIC_TAGS: {}",
+                e
+            )))?,
         }
-        tags.insert("uri.method".to_owned(), req_head.method.to_string());
-        // `uri.path` causes too much cardinality for influx but keep it in
-        // extra for sentry
-        extra.insert("uri.path".to_owned(), req_head.uri.to_string());
-        */
         Ok(Tags { tags, extra })
     }
+
+    /// Return the static tags along with the tags of the request's headers
+    pub fn from_request(request: &Request<'_>) -> Tags {
+        request
+            .local_cache(|| {
+                let mut tags = match request.guard::<State<'_, Tags>>() {
+                    Outcome::Success(tags) => tags.inner().clone(),
+                    _ => Tags::default(),
+                };
+                if let Some(ua) = request.headers().get_one("User-Agent") {
+                    tags.extend(user_agent_tags(ua));
+                    // The raw User-Agent is too high cardinality for metrics
+                    tags.extra.insert("ua".to_owned(), ua.to_owned());
+                }
+                tags
+            })
+            .clone()
+    }
+}
+
+/// How a tag's values are bucketed
+#[derive(Clone, Debug, PartialEq)]
+enum Bucketing {
+    /// Hashed into a number of buckets
+    Hash(u64),
+    /// Kept when listed, otherwise OTHER_BUCKET
+    Values(HashSet<String>),
+}
+
+impl Bucketing {
+    fn from_value(name: &str, value: &Value) -> HandlerResult<Bucketing> {
+        let invalid =
+            || HandlerError::internal(format!("Invalid metric_tag_buckets for: {:?}", name));
+        match value {
+            Value::Integer(buckets) if *buckets > 0 => Ok(Bucketing::Hash(*buckets as u64)),
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_str().map(str::to_owned))
+                .collect::<Option<HashSet<_>>>()
+                .map(Bucketing::Values)
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn bucket(&self, value: &str) -> String {
+        match self {
+            Bucketing::Hash(buckets) => (fnv1a(value) % buckets).to_string(),
+            Bucketing::Values(values) if values.contains(value) => value.to_owned(),
+            Bucketing::Values(_) => OTHER_BUCKET.to_owned(),
+        }
+    }
+}
+
+/// The 64 bit FNV-1a hash of a value (stable across processes, unlike std's
+/// Hasher)
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Limits which tags (and tag values) are emitted with metrics
+#[derive(Clone, Debug, Default)]
+pub struct TagPolicy {
+    /// Tag names emitted (None for all)
+    allowlist: Option<HashSet<String>>,
+    buckets: HashMap<String, Bucketing>,
+}
+
+impl TagPolicy {
+    pub fn from_config(config: &Config) -> HandlerResult<TagPolicy> {
+        let allowlist = match config.get_slice("metric_tag_allowlist") {
+            Ok(names) => Some(
+                names
+                    .iter()
+                    .map(|name| name.as_str().map(str::to_owned))
+                    .collect::<Option<HashSet<_>>>()
+                    .ok_or_else(|| {
+                        HandlerError::internal("Invalid ROCKET_METRIC_TAG_ALLOWLIST".to_owned())
+                    })?,
+            ),
+            Err(ConfigError::Missing(_)) => None,
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_METRIC_TAG_ALLOWLIST: {}",
+                e
+            )))?,
+        };
+        let mut buckets = HashMap::new();
+        match config.get_table("metric_tag_buckets") {
+            Ok(table) => {
+                for (name, value) in table {
+                    buckets.insert(name.to_owned(), Bucketing::from_value(name, value)?);
+                }
+            }
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_METRIC_TAG_BUCKETS: {}",
+                e
+            )))?,
+        }
+        Ok(TagPolicy { allowlist, buckets })
+    }
+
+    /// Return the value to emit for a tag, or None if it's dropped
+    pub fn apply(&self, name: &str, value: &str) -> Option<String> {
+        let allowed = match &self.allowlist {
+            Some(_) if FIXED_TAGS.contains(&name) => true,
+            Some(allowlist) => allowlist.contains(name),
+            None => true,
+        };
+        if !allowed {
+            return None;
+        }
+        Some(match self.buckets.get(name) {
+            Some(bucketing) => bucketing.bucket(value),
+            None => value.to_owned(),
+        })
+    }
 }
 
 impl From<Tags> for BTreeMap<String, String> {
@@ -139,3 +291,73 @@ impl KV for Tags {
     }
 }
 */
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use rocket::config::{Config, Environment, Value};
+
+    use super::{TagPolicy, Tags};
+
+    #[test]
+    fn test_static_tags() {
+        let config = Config::build(Environment::Development).unwrap();
+        assert!(Tags::init(&config).unwrap().tags.is_empty());
+        let mut table = BTreeMap::new();
+        table.insert("env".to_owned(), Value::from("stage"));
+        let config = Config::build(Environment::Development)
+            .extra("metric_tags", table)
+            .unwrap();
+        let tags = Tags::init(&config).unwrap();
+        assert_eq!(tags.tags.get("env").map(String::as_str), Some("stage"));
+
+        let mut table = BTreeMap::new();
+        table.insert("env".to_owned(), Value::from(1));
+        let config = Config::build(Environment::Development)
+            .extra("metric_tags", table)
+            .unwrap();
+        assert!(Tags::init(&config).is_err());
+    }
+
+    #[test]
+    fn test_tag_policy() {
+        let policy = TagPolicy::default();
+        assert_eq!(policy.apply("broadcaster", "foo"), Some("foo".to_owned()));
+        assert_eq!(
+            policy.apply("namespace", "default"),
+            Some("default".to_owned())
+        );
+
+        let mut buckets = BTreeMap::new();
+        buckets.insert("channel_id".to_owned(), Value::from(4));
+        buckets.insert(
+            "broadcaster".to_owned(),
+            Value::Array(vec![Value::from("foo")]),
+        );
+        let config = Config::build(Environment::Development)
+            .extra("metric_tag_allowlist", vec!["broadcaster", "channel_id"])
+            .extra("metric_tag_buckets", buckets)
+            .unwrap();
+        let policy = TagPolicy::from_config(&config).unwrap();
+        assert_eq!(policy.apply("namespace", "default"), None);
+        assert_eq!(
+            policy.apply("route", "broadcast"),
+            Some("broadcast".to_owned())
+        );
+        assert_eq!(policy.apply("errno", "121"), Some("121".to_owned()));
+        assert_eq!(policy.apply("broadcaster", "foo"), Some("foo".to_owned()));
+        assert_eq!(policy.apply("broadcaster", "bar"), Some("other".to_owned()));
+        let bucket = policy.apply("channel_id", "bar").unwrap();
+        assert!(bucket.parse::<u64>().unwrap() < 4);
+        // Stable
+        assert_eq!(policy.apply("channel_id", "bar"), Some(bucket));
+
+        let mut buckets = BTreeMap::new();
+        buckets.insert("channel_id".to_owned(), Value::from(0));
+        let config = Config::build(Environment::Development)
+            .extra("metric_tag_buckets", buckets)
+            .unwrap();
+        assert!(TagPolicy::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,474 @@
+/// Distributed tracing via OpenTelemetry
+///
+/// When `trace_exporter` is set in the rocket Config, spans are recorded for
+/// each request (continuing the trace of its W3C `traceparent` header), its
+/// auth check, its db pool checkout and its SQL queries. Webhook deliveries
+/// continue the trace of the request that queued them and propagate it to
+/// subscribers via `traceparent`.
+///
+/// Spans are exported in batches encoded as OTLP/JSON, per `trace_exporter`:
+///
+/// - `otlp`: POSTed to the OTLP/HTTP `trace_otlp_endpoint` (default
+///   `http://localhost:4318/v1/traces`)
+/// - `stdout`: written to stdout, a batch per line
+/// - `file`: appended to the `trace_file` path, a batch per line
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::future::{self, Future};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{SpanId, SpanKind, Status, TraceContextExt, TraceError, Tracer as _},
+    Context, ContextGuard, KeyValue, Value,
+};
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    propagation::TraceContextPropagator,
+    runtime::TokioCurrentThread,
+    trace::{self as sdktrace, BatchSpanProcessor, TracerProvider},
+    Resource,
+};
+use rocket::{
+    config::ConfigError,
+    fairing::{Fairing, Info, Kind},
+    http::HeaderMap,
+    Config, Data, Request, Response,
+};
+use serde_json::json;
+use slog::{error, Logger};
+
+use crate::error::{HandlerError, HandlerResult};
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+const TRACER_NAME: &str = env!("CARGO_PKG_NAME");
+const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4318/v1/traces";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// The context of the request being handled by this thread
+    static REQUEST_CONTEXT: RefCell<Option<ContextGuard>> = RefCell::new(None);
+}
+
+/// Where exported spans are written
+#[derive(Debug)]
+enum Destination {
+    Otlp {
+        endpoint: String,
+        agent: ureq::Agent,
+    },
+    Stdout,
+    File(File),
+}
+
+/// A SpanExporter encoding spans as OTLP/JSON
+#[derive(Debug)]
+struct JsonSpanExporter {
+    destination: Destination,
+}
+
+impl JsonSpanExporter {
+    fn write(&mut self, batch: &[SpanData]) -> ExportResult {
+        let body = encode_spans(batch).to_string();
+        match &mut self.destination {
+            Destination::Otlp { endpoint, agent } => agent
+                .post(endpoint)
+                .set("Content-Type", "application/json")
+                .send_string(&body)
+                .map(|_| ())
+                .map_err(|e| TraceError::from(format!("OTLP export failed: {}", e))),
+            Destination::Stdout => writeln!(io::stdout().lock(), "{}", body)
+                .map_err(|e| TraceError::from(format!("Span export failed: {}", e))),
+            Destination::File(file) => writeln!(file, "{}", body)
+                .and_then(|_| file.flush())
+                .map_err(|e| TraceError::from(format!("Span export failed: {}", e))),
+        }
+    }
+}
+
+impl SpanExporter for JsonSpanExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        Box::pin(future::ready(self.write(&batch)))
+    }
+}
+
+fn encode_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        // int64s are strings in OTLP/JSON
+        Value::I64(value) => json!({ "intValue": value.to_string() }),
+        Value::F64(value) => json!({ "doubleValue": value }),
+        _ => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+fn encode_attributes<'a>(
+    attributes: impl Iterator<Item = (&'a str, &'a Value)>,
+) -> serde_json::Value {
+    attributes
+        .map(|(key, value)| json!({ "key": key, "value": encode_value(value) }))
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn encode_span(span: &SpanData) -> serde_json::Value {
+    let kind = match span.span_kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+        SpanKind::Producer => 4,
+        SpanKind::Consumer => 5,
+    };
+    let status = match &span.status {
+        Status::Unset => json!({}),
+        Status::Ok => json!({ "code": 1 }),
+        Status::Error { description } => json!({ "code": 2, "message": description }),
+    };
+    let mut encoded = json!({
+        "traceId": span.span_context.trace_id().to_string(),
+        "spanId": span.span_context.span_id().to_string(),
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nanos(span.start_time),
+        "endTimeUnixNano": unix_nanos(span.end_time),
+        "attributes": encode_attributes(
+            span.attributes
+                .iter()
+                .map(|attribute| (attribute.key.as_str(), &attribute.value))
+        ),
+        "status": status,
+    });
+    if span.parent_span_id != SpanId::INVALID {
+        encoded["parentSpanId"] = span.parent_span_id.to_string().into();
+    }
+    encoded
+}
+
+/// Encode spans as an OTLP/JSON `ExportTraceServiceRequest`
+///
+/// The spans all originate from this process' TracerProvider, so share the
+/// same resource and instrumentation scope.
+fn encode_spans(batch: &[SpanData]) -> serde_json::Value {
+    let first = match batch.first() {
+        Some(first) => first,
+        None => return json!({ "resourceSpans": [] }),
+    };
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": encode_attributes(
+                    first.resource.iter().map(|(key, value)| (key.as_str(), value))
+                ),
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": first.instrumentation_lib.name,
+                    "version": first.instrumentation_lib.version,
+                },
+                "spans": batch.iter().map(encode_span).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// The configured TracerProvider
+pub struct Tracing {
+    provider: TracerProvider,
+}
+
+impl Tracing {
+    /// Return a Tracing, or None if tracing is disabled
+    pub fn from_config(config: &Config) -> HandlerResult<Option<Tracing>> {
+        let exporter = match config.get_str("trace_exporter") {
+            Ok(exporter) => exporter,
+            Err(ConfigError::Missing(_)) => return Ok(None),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_TRACE_EXPORTER: {}",
+                e
+            )))?,
+        };
+        let destination = match exporter {
+            "otlp" => {
+                let endpoint = match config.get_string("trace_otlp_endpoint") {
+                    Ok(endpoint) => endpoint,
+                    Err(ConfigError::Missing(_)) => DEFAULT_OTLP_ENDPOINT.to_owned(),
+                    Err(e) => Err(HandlerError::internal(format!(
+                        "Invalid ROCKET_TRACE_OTLP_ENDPOINT: {}",
+                        e
+                    )))?,
+                };
+                let tls = native_tls::TlsConnector::new().map_err(|e| {
+                    HandlerError::internal(format!("Could not initialize TLS: {}", e))
+                })?;
+                let agent = ureq::AgentBuilder::new()
+                    .timeout(EXPORT_TIMEOUT)
+                    .tls_connector(Arc::new(tls))
+                    .build();
+                Destination::Otlp { endpoint, agent }
+            }
+            "stdout" => Destination::Stdout,
+            "file" => {
+                let path = config.get_str("trace_file").map_err(|_| {
+                    HandlerError::internal("Invalid or undefined ROCKET_TRACE_FILE".to_owned())
+                })?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        HandlerError::internal(format!(
+                            "Could not open trace file {:?}: {}",
+                            path, e
+                        ))
+                    })?;
+                Destination::File(file)
+            }
+            _ => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_TRACE_EXPORTER: {:?} (expected otlp, stdout or file)",
+                exporter
+            )))?,
+        };
+        let processor =
+            BatchSpanProcessor::builder(JsonSpanExporter { destination }, TokioCurrentThread)
+                .build();
+        let resource = Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]);
+        let provider = TracerProvider::builder()
+            .with_span_processor(processor)
+            .with_config(sdktrace::config().with_resource(resource))
+            .build();
+        Ok(Some(Tracing { provider }))
+    }
+
+    /// Install as the global TracerProvider, logging export errors
+    pub fn install(&self, log: Logger) {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        global::set_tracer_provider(self.provider.clone());
+        let _ = global::set_error_handler(move |e| error!(log, "Tracing error: {}", e));
+    }
+
+    /// Export all the ended spans
+    #[cfg(test)]
+    pub fn force_flush(&self) {
+        self.provider.force_flush();
+    }
+}
+
+/// Run `f` within a new span, a child of the current span, recording its
+/// error (if any)
+pub fn in_span<T, E: Display>(
+    name: &'static str,
+    kind: SpanKind,
+    f: impl FnOnce(&Context) -> Result<T, E>,
+) -> Result<T, E> {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer.span_builder(name).with_kind(kind).start(&tracer);
+    let cx = Context::current_with_span(span);
+    let _guard = cx.clone().attach();
+    let result = f(&cx);
+    if let Err(e) = &result {
+        cx.span().set_status(Status::error(e.to_string()));
+    }
+    cx.span().end();
+    result
+}
+
+/// Return the trace id of the current span, if any
+pub fn trace_id() -> Option<String> {
+    let cx = Context::current();
+    let span_context = cx.span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Return the `traceparent` header of the current span, if any
+pub fn traceparent() -> Option<String> {
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut fields)
+    });
+    fields.remove(TRACEPARENT_HEADER)
+}
+
+/// Return the remote context of a `traceparent` header
+pub fn remote_context(traceparent: Option<&str>) -> Context {
+    let mut fields = HashMap::new();
+    if let Some(traceparent) = traceparent {
+        fields.insert(TRACEPARENT_HEADER.to_owned(), traceparent.to_owned());
+    }
+    global::get_text_map_propagator(|propagator| propagator.extract(&fields))
+}
+
+struct HeaderExtractor<'a, 'h>(&'a HeaderMap<'h>);
+
+impl<'a, 'h> Extractor for HeaderExtractor<'a, 'h> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    /// Only the W3C trace context headers are listed (rocket's HeaderMap
+    /// doesn't expose its names)
+    fn keys(&self) -> Vec<&str> {
+        [TRACEPARENT_HEADER, TRACESTATE_HEADER]
+            .iter()
+            .copied()
+            .filter(|name| self.0.contains(name))
+            .collect()
+    }
+}
+
+/// Records a span per request, the current span while it's handled
+///
+/// Rocket handles each request entirely on one thread, so its span is kept
+/// in the thread's current Context between `on_request` and `on_response`.
+pub struct RequestTracing;
+
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(request.method().as_str())
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", request.method().as_str()),
+                KeyValue::new("http.target", request.uri().path().to_owned()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+        REQUEST_CONTEXT.with(|current| {
+            // Restore the prior context of an unfinished request before
+            // replacing it
+            drop(current.borrow_mut().take());
+            *current.borrow_mut() = Some(cx.attach());
+        });
+    }
+
+    fn on_response(&self, request: &Request<'_>, response: &mut Response<'_>) {
+        let guard = match REQUEST_CONTEXT.with(|current| current.borrow_mut().take()) {
+            Some(guard) => guard,
+            None => return,
+        };
+        let cx = Context::current();
+        let span = cx.span();
+        if let Some(route) = request.route() {
+            let route = route.uri.path().to_owned();
+            span.update_name(format!("{} {}", request.method().as_str(), route));
+            span.set_attribute(KeyValue::new("http.route", route));
+        }
+        let status = response.status();
+        span.set_attribute(KeyValue::new("http.status_code", i64::from(status.code)));
+        if status.code >= 500 {
+            span.set_status(Status::error(status.reason));
+        }
+        span.end();
+        drop(guard);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+
+    use opentelemetry::trace::{
+        Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider as _,
+    };
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+    use rocket::config::{Config, Environment};
+
+    use super::{remote_context, trace_id, traceparent, Destination, JsonSpanExporter, Tracing};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_config() {
+        let config = Config::build(Environment::Development).unwrap();
+        assert!(Tracing::from_config(&config).unwrap().is_none());
+        let config = Config::build(Environment::Development)
+            .extra("trace_exporter", "zipkin")
+            .unwrap();
+        assert!(Tracing::from_config(&config).is_err());
+        let config = Config::build(Environment::Development)
+            .extra("trace_exporter", "file")
+            .unwrap();
+        assert!(Tracing::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let _guard = remote_context(Some(TRACEPARENT)).attach();
+        assert_eq!(
+            trace_id().as_deref(),
+            Some("0af7651916cd43dd8448eb211c80319c")
+        );
+        assert_eq!(traceparent().as_deref(), Some(TRACEPARENT));
+        assert!(!remote_context(Some("garbage")).has_active_span());
+    }
+
+    #[test]
+    fn test_export() {
+        let path = std::env::temp_dir().join("megaphone-test_export.json");
+        let exporter = JsonSpanExporter {
+            destination: Destination::File(File::create(&path).unwrap()),
+        };
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .build();
+        let tracer = provider.tracer("megaphone");
+        let mut span = tracer
+            .span_builder("GET /v1/broadcasts")
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![KeyValue::new("http.status_code", 500i64)])
+            .start_with_context(&tracer, &remote_context(Some(TRACEPARENT)));
+        span.set_status(Status::error("Internal Server Error"));
+        span.end();
+        provider.force_flush();
+
+        let exported: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&path).unwrap().trim()).unwrap();
+        let scope_spans = &exported["resourceSpans"][0]["scopeSpans"][0];
+        assert_eq!(scope_spans["scope"]["name"], "megaphone");
+        let span = &scope_spans["spans"][0];
+        assert_eq!(span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(span["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(span["name"], "GET /v1/broadcasts");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(
+            span["attributes"][0],
+            serde_json::json!({"key": "http.status_code", "value": {"intValue": "500"}})
+        );
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(span["status"]["message"], "Internal Server Error");
+    }
+}
@@ -0,0 +1,140 @@
+/// User-Agent parsing for metric tags
+///
+/// Clients are identified by the families of their browser and OS (as parsed
+/// by woothee) and by the leading product token of their User-Agent (e.g.
+/// `autopush/1.68.0`). Only well known families and major versions are kept
+/// (others becoming `Other`), bounding the cardinality of the tags.
+use std::collections::HashMap;
+
+use woothee::parser::{Parser, WootheeResult};
+
+// List of valid user-agent attributes to keep, anything not in this list is
+// considered 'Other'.
+const VALID_UA_BROWSER: &[&str] = &["Chrome", "Firefox", "Safari", "Opera"];
+
+// See dataset.rs in https://github.com/woothee/woothee-rust for the full list
+// (WootheeResult's 'os' field may fall back to its 'name' field). Windows has
+// many values and we only care that it's Windows.
+const VALID_UA_OS: &[&str] = &["Firefox OS", "Linux", "Mac OSX"];
+
+// Product tokens of the known (non browser) clients: autopush and the
+// publishers' tooling.
+const VALID_UA_CLIENT: &[&str] = &[
+    "autoconnect",
+    "autoendpoint",
+    "autopush",
+    "curl",
+    "Go-http-client",
+    "kinto_http",
+    "node-fetch",
+    "python-requests",
+    "Wget",
+];
+
+const OTHER: &str = "Other";
+
+/// Parse a User-Agent, returning its base OS and browser families
+pub fn parse_user_agent(agent: &str) -> (WootheeResult<'_>, &str, &str) {
+    let parser = Parser::new();
+    let wresult = parser.parse(agent).unwrap_or_default();
+
+    // Determine a base os/browser for metrics' tags
+    let metrics_os = if wresult.os.starts_with("Windows") {
+        "Windows"
+    } else if VALID_UA_OS.contains(&wresult.os) {
+        wresult.os
+    } else {
+        OTHER
+    };
+    let metrics_browser = if VALID_UA_BROWSER.contains(&wresult.name) {
+        wresult.name
+    } else {
+        OTHER
+    };
+    (wresult, metrics_os, metrics_browser)
+}
+
+/// Return the known client of a User-Agent's leading product token, along
+/// with its major version
+pub fn parse_client(agent: &str) -> (&str, Option<&str>) {
+    let product = agent.split_whitespace().next().unwrap_or_default();
+    let (name, version) = product.split_once('/').unwrap_or((product, ""));
+    match VALID_UA_CLIENT.iter().find(|client| **client == name) {
+        Some(client) => (client, major_version(version)),
+        None => (OTHER, None),
+    }
+}
+
+/// Return the major part of a version (e.g. `1` of `1.68.0`)
+fn major_version(version: &str) -> Option<&str> {
+    let major = version.split('.').next().unwrap_or_default();
+    if !major.is_empty() && major.bytes().all(|b| b.is_ascii_digit()) {
+        Some(major)
+    } else {
+        None
+    }
+}
+
+/// Return the metric tags of a User-Agent
+pub fn user_agent_tags(agent: &str) -> HashMap<String, String> {
+    let mut tags = HashMap::new();
+    let (wresult, metrics_os, metrics_browser) = parse_user_agent(agent);
+    tags.insert("ua.os.family".to_owned(), metrics_os.to_owned());
+    tags.insert("ua.browser.family".to_owned(), metrics_browser.to_owned());
+    if metrics_browser != OTHER {
+        if let Some(major) = major_version(wresult.version) {
+            tags.insert("ua.browser.ver".to_owned(), major.to_owned());
+        }
+    }
+    let (client, client_version) = parse_client(agent);
+    tags.insert("ua.client".to_owned(), client.to_owned());
+    if let Some(major) = client_version {
+        tags.insert("ua.client.ver".to_owned(), major.to_owned());
+    }
+    tags
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_client, parse_user_agent, user_agent_tags};
+
+    #[test]
+    fn test_linux() {
+        let agent =
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:118.0) Gecko/20100101 Firefox/118.0";
+        let (_, metrics_os, metrics_browser) = parse_user_agent(agent);
+        assert_eq!(metrics_os, "Linux");
+        assert_eq!(metrics_browser, "Firefox");
+        let tags = user_agent_tags(agent);
+        assert_eq!(tags["ua.browser.ver"], "118");
+        assert_eq!(tags["ua.client"], "Other");
+        assert!(!tags.contains_key("ua.client.ver"));
+    }
+
+    #[test]
+    fn test_windows() {
+        let agent =
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:118.0) Gecko/20100101 Firefox/118.0";
+        let (_, metrics_os, _) = parse_user_agent(agent);
+        assert_eq!(metrics_os, "Windows");
+    }
+
+    #[test]
+    fn test_clients() {
+        assert_eq!(parse_client("autopush/1.68.0"), ("autopush", Some("1")));
+        assert_eq!(
+            parse_client("python-requests/2.31.0"),
+            ("python-requests", Some("2"))
+        );
+        assert_eq!(parse_client("curl"), ("curl", None));
+        assert_eq!(parse_client("my-script/0.1"), ("Other", None));
+        assert_eq!(parse_client(""), ("Other", None));
+
+        let tags = user_agent_tags("autopush/1.68.0 (build abc123)");
+        assert_eq!(tags["ua.client"], "autopush");
+        assert_eq!(tags["ua.client.ver"], "1");
+        assert_eq!(tags["ua.os.family"], "Other");
+        assert_eq!(tags["ua.browser.family"], "Other");
+        assert!(!tags.contains_key("ua.browser.ver"));
+    }
+}
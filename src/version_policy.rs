@@ -0,0 +1,280 @@
+/// Per-broadcaster version format policies
+///
+/// Driven from the `version_policies` table in the rocket Config, keyed by
+/// broadcaster id, e.g.
+///
+/// ```toml
+/// [development.version_policies]
+/// kinto = { format = "integer", monotonic = true }
+/// atmo = { format = "regex", pattern = "^v[0-9]+$" }
+/// ```
+///
+/// Broadcasters without a policy accept any version passing the basic
+/// `VersionInput` checks.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use regex::Regex;
+use rocket::config::{ConfigError, Value};
+use rocket::Config;
+
+use crate::error::{HandlerError, HandlerErrorKind, HandlerResult};
+
+/// The "No Operation" version, always accepted regardless of policy
+pub const NOP_VERSION: &str = "____NOP____";
+
+#[derive(Debug)]
+enum VersionFormat {
+    Any,
+    Regex(Regex),
+    Semver,
+    Integer,
+}
+
+impl VersionFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            VersionFormat::Any => "any",
+            VersionFormat::Regex(_) => "regex",
+            VersionFormat::Semver => "semver",
+            VersionFormat::Integer => "integer",
+        }
+    }
+
+    /// Validate the version's format
+    fn validate(&self, version: &str) -> Result<(), String> {
+        match self {
+            VersionFormat::Any => Ok(()),
+            VersionFormat::Regex(re) => {
+                if re.is_match(version) {
+                    Ok(())
+                } else {
+                    Err(format!("must match the pattern: {}", re.as_str()))
+                }
+            }
+            VersionFormat::Semver => semver::Version::parse(version)
+                .map(|_| ())
+                .map_err(|e| format!("must be a semantic version ({})", e)),
+            VersionFormat::Integer => parse_integer(version)
+                .map(|_| ())
+                .ok_or_else(|| "must be a non-negative integer".to_owned()),
+        }
+    }
+
+    /// Whether versions of this format have an ordering
+    fn is_ordered(&self) -> bool {
+        matches!(self, VersionFormat::Semver | VersionFormat::Integer)
+    }
+
+    /// Compare two versions of this format, returning None when either
+    /// can't be ordered
+    fn compare(&self, a: &str, b: &str) -> Option<Ordering> {
+        match self {
+            VersionFormat::Semver => {
+                let a = semver::Version::parse(a).ok()?;
+                let b = semver::Version::parse(b).ok()?;
+                Some(a.cmp(&b))
+            }
+            VersionFormat::Integer => Some(parse_integer(a)?.cmp(&parse_integer(b)?)),
+            VersionFormat::Any | VersionFormat::Regex(_) => None,
+        }
+    }
+}
+
+fn parse_integer(version: &str) -> Option<u64> {
+    if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    version.parse().ok()
+}
+
+#[derive(Debug)]
+pub struct VersionPolicy {
+    format: VersionFormat,
+    /// Reject versions older than the currently stored version
+    monotonic: bool,
+}
+
+impl VersionPolicy {
+    fn from_value(broadcaster_id: &str, value: &Value) -> HandlerResult<VersionPolicy> {
+        let invalid = |msg: &str| {
+            HandlerError::internal(format!(
+                "Invalid version_policies entry for {:?}: {}",
+                broadcaster_id, msg
+            ))
+        };
+        let table = value.as_table().ok_or_else(|| invalid("not a table"))?;
+        let format = match table.get("format").map(|v| v.as_str()) {
+            None | Some(Some("any")) => VersionFormat::Any,
+            Some(Some("semver")) => VersionFormat::Semver,
+            Some(Some("integer")) => VersionFormat::Integer,
+            Some(Some("regex")) => {
+                let pattern = table
+                    .get("pattern")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid("regex format requires a pattern"))?;
+                let re = Regex::new(pattern).map_err(|e| invalid(&e.to_string()))?;
+                VersionFormat::Regex(re)
+            }
+            Some(_) => Err(invalid("unknown format"))?,
+        };
+        let monotonic = match table.get("monotonic") {
+            None => false,
+            Some(v) => v
+                .as_bool()
+                .ok_or_else(|| invalid("monotonic must be a boolean"))?,
+        };
+        if monotonic && !format.is_ordered() {
+            Err(invalid(&format!(
+                "monotonic is not supported for the {} format",
+                format.name()
+            )))?
+        }
+        Ok(VersionPolicy { format, monotonic })
+    }
+
+    /// Check a new version against this policy and the currently stored
+    /// version (if any)
+    pub fn check(&self, version: &str, current: Option<&str>) -> HandlerResult<()> {
+        if version == NOP_VERSION {
+            return Ok(());
+        }
+        self.format
+            .validate(version)
+            .map_err(HandlerErrorKind::VersionPolicyError)?;
+        if !self.monotonic {
+            return Ok(());
+        }
+        // Stored versions predating the policy may not be comparable
+        if let Some(current) = current {
+            if self.format.compare(version, current) == Some(Ordering::Less) {
+                Err(HandlerErrorKind::VersionRegressionError(
+                    version.to_owned(),
+                    current.to_owned(),
+                ))?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VersionPolicies {
+    policies: HashMap<String, VersionPolicy>,
+}
+
+impl VersionPolicies {
+    pub fn from_config(config: &Config) -> HandlerResult<VersionPolicies> {
+        let table = match config.get_table("version_policies") {
+            Ok(table) => table,
+            Err(ConfigError::Missing(_)) => return Ok(VersionPolicies::default()),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_VERSION_POLICIES: {}",
+                e
+            )))?,
+        };
+        let mut policies = HashMap::new();
+        for (broadcaster_id, value) in table {
+            policies.insert(
+                broadcaster_id.to_owned(),
+                VersionPolicy::from_value(broadcaster_id, value)?,
+            );
+        }
+        Ok(VersionPolicies { policies })
+    }
+
+    pub fn get(&self, broadcaster_id: &str) -> Option<&VersionPolicy> {
+        self.policies.get(broadcaster_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use std::collections::BTreeMap;
+
+    use super::{VersionPolicies, NOP_VERSION};
+    use crate::error::HandlerResult;
+
+    fn policies(entries: Vec<(&str, Vec<(&str, Value)>)>) -> HandlerResult<VersionPolicies> {
+        let mut table = BTreeMap::new();
+        for (id, fields) in entries {
+            let fields: BTreeMap<String, Value> =
+                fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect();
+            table.insert(id.to_owned(), Value::Table(fields));
+        }
+        let config = Config::build(Environment::Development)
+            .extra("version_policies", table)
+            .unwrap();
+        VersionPolicies::from_config(&config)
+    }
+
+    #[test]
+    fn test_formats() {
+        let policies = policies(vec![
+            ("int", vec![("format", "integer".into())]),
+            ("sem", vec![("format", "semver".into())]),
+            (
+                "re",
+                vec![("format", "regex".into()), ("pattern", "^v[0-9]+$".into())],
+            ),
+        ])
+        .unwrap();
+        let int = policies.get("int").unwrap();
+        assert!(int.check("42", None).is_ok());
+        assert!(int.check("v42", None).is_err());
+        assert!(int.check("-1", None).is_err());
+        let sem = policies.get("sem").unwrap();
+        assert!(sem.check("1.2.3", None).is_ok());
+        assert!(sem.check("1.2", None).is_err());
+        let re = policies.get("re").unwrap();
+        assert!(re.check("v1", None).is_ok());
+        assert!(re.check("1", None).is_err());
+        assert!(re.check(NOP_VERSION, None).is_ok());
+        assert!(policies.get("other").is_none());
+    }
+
+    #[test]
+    fn test_monotonic() {
+        let policies = policies(vec![
+            (
+                "int",
+                vec![("format", "integer".into()), ("monotonic", true.into())],
+            ),
+            (
+                "sem",
+                vec![("format", "semver".into()), ("monotonic", true.into())],
+            ),
+        ])
+        .unwrap();
+        let int = policies.get("int").unwrap();
+        assert!(int.check("10", Some("9")).is_ok());
+        assert!(int.check("10", Some("10")).is_ok());
+        assert!(int.check("9", Some("10")).is_err());
+        // legacy, non-conforming stored versions aren't compared
+        assert!(int.check("9", Some("abc")).is_ok());
+        let sem = policies.get("sem").unwrap();
+        assert!(sem.check("1.10.0", Some("1.9.0")).is_ok());
+        assert!(sem.check("1.0.0", Some("1.0.1")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(policies(vec![("foo", vec![("format", "bogus".into())])]).is_err());
+        assert!(policies(vec![("foo", vec![("format", "regex".into())])]).is_err());
+        assert!(policies(vec![(
+            "foo",
+            vec![("format", "regex".into()), ("pattern", "(".into())]
+        )])
+        .is_err());
+        assert!(policies(vec![(
+            "foo",
+            vec![
+                ("format", "regex".into()),
+                ("pattern", ".*".into()),
+                ("monotonic", true.into())
+            ]
+        )])
+        .is_err());
+    }
+}
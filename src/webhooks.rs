@@ -0,0 +1,634 @@
+/// Webhook notifications of new versions
+///
+/// Subscribers are configured via the `webhooks` table in the rocket Config,
+/// keyed by subscriber name, e.g.
+///
+/// ```toml
+/// [development.webhooks.autopush]
+/// url = "https://autopush.example.com/megaphone"
+/// secret = "a shared secret"
+/// channels = ["shield/*", "kinto/remote-settings"]
+/// ```
+///
+/// `channels` filters the notified Broadcasts by id (or every Broadcast of a
+/// broadcaster via `<broadcaster_id>/*`), defaulting to all of them.
+/// Subscribers are notified of a single `namespace`'s Broadcasts, defaulting
+/// to the default namespace.
+///
+/// Every new version is queued for each matching subscriber in the
+/// `webhook_deliveries` table within the transaction storing it, then
+/// delivered by a background thread every `webhook_interval` seconds from the
+/// rocket Config (default 1, 0 disables delivery). Deliveries are POSTed as
+/// JSON, signed by the `Megaphone-Webhook-Signature` header: `sha256=`
+/// followed by the hex encoded HMAC-SHA256 of the body keyed by the
+/// subscriber's secret. Failed deliveries are retried with exponential
+/// backoff, up to `webhook_max_attempts` (default 10) attempts. When tracing
+/// is enabled, deliveries continue the trace of the request queueing them
+/// and carry its `traceparent` header.
+use std::cmp;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use opentelemetry::trace::SpanKind;
+use rocket::config::{ConfigError, Value};
+use rocket::Config;
+use slog::{error, info, warn, Logger};
+
+use crate::db::{models::WebhookDelivery, MysqlPool, TracedConnection};
+use crate::error::{HandlerError, HandlerResult};
+use crate::metrics::Metrics;
+use crate::namespace::{Namespace, DEFAULT_NAMESPACE};
+use crate::shutdown::Shutdown;
+use crate::tags::Tags;
+use crate::telemetry::{self, TRACEPARENT_HEADER};
+
+pub const SIGNATURE_HEADER: &str = "Megaphone-Webhook-Signature";
+pub const DELIVERY_HEADER: &str = "Megaphone-Webhook-Delivery";
+
+/// Default number of seconds between deliveries
+const DEFAULT_WEBHOOK_INTERVAL: i64 = 1;
+const DEFAULT_MAX_ATTEMPTS: i64 = 10;
+
+/// Maximum length of a subscriber name
+const MAX_SUBSCRIBER_LEN: usize = 64;
+
+/// Number of deliveries claimed at a time
+const DELIVERY_BATCH_SIZE: i64 = 10;
+/// Timeout of a single delivery
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long claimed deliveries are leased for: exceeding the time taken to
+/// attempt an entire batch
+const DELIVERY_LEASE_SECS: i64 = 300;
+
+/// Delay before the first retry, doubling on every further attempt
+const RETRY_BASE_SECS: i64 = 2;
+const RETRY_MAX_SECS: i64 = 60 * 60;
+
+#[derive(Debug)]
+pub struct Subscriber {
+    pub name: String,
+    url: String,
+    secret: String,
+    namespace: String,
+    /// Broadcast ids (or `<broadcaster_id>/*`), None matching all
+    channels: Option<Vec<String>>,
+}
+
+impl Subscriber {
+    fn from_value(name: &str, value: &Value) -> HandlerResult<Subscriber> {
+        let invalid = |msg: &str| {
+            HandlerError::internal(format!("Invalid webhooks entry for {:?}: {}", name, msg))
+        };
+        if name.len() > MAX_SUBSCRIBER_LEN {
+            Err(invalid("name too long"))?
+        }
+        let table = value.as_table().ok_or_else(|| invalid("not a table"))?;
+        let string = |key: &str| {
+            table
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+                .ok_or_else(|| invalid(&format!("{} must be a string", key)))
+        };
+        let channels = match table.get("channels") {
+            None => None,
+            Some(channels) => Some(
+                channels
+                    .as_array()
+                    .and_then(|channels| {
+                        channels
+                            .iter()
+                            .map(|channel| channel.as_str().map(str::to_owned))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or_else(|| invalid("channels must be an array of strings"))?,
+            ),
+        };
+        let namespace = match table.get("namespace") {
+            None => DEFAULT_NAMESPACE.to_owned(),
+            Some(namespace) => namespace
+                .as_str()
+                .filter(|namespace| Namespace::is_valid(namespace))
+                .map(str::to_owned)
+                .ok_or_else(|| invalid("namespace must be a valid namespace"))?,
+        };
+        Ok(Subscriber {
+            name: name.to_owned(),
+            url: string("url")?,
+            secret: string("secret")?,
+            namespace,
+            channels,
+        })
+    }
+
+    /// Whether this subscriber is notified of the Broadcast's new versions
+    pub fn matches(&self, namespace: &str, broadcaster_id: &str, bchannel_id: &str) -> bool {
+        if namespace != self.namespace {
+            return false;
+        }
+        let channels = match &self.channels {
+            Some(channels) => channels,
+            None => return true,
+        };
+        channels
+            .iter()
+            .any(|channel| match channel.split_once('/') {
+                Some((bid, "*")) => bid == broadcaster_id,
+                Some((bid, cid)) => bid == broadcaster_id && cid == bchannel_id,
+                None => channel == "*",
+            })
+    }
+
+    /// Return the `Megaphone-Webhook-Signature` of a delivery's body
+    pub fn sign(&self, body: &[u8]) -> HandlerResult<String> {
+        let sign = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+            let key = PKey::hmac(self.secret.as_bytes())?;
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            signer.update(body)?;
+            signer.sign_to_vec()
+        };
+        let mac = sign().map_err(|e| HandlerError::internal(format!("HMAC failed: {}", e)))?;
+        Ok(mac.iter().fold("sha256=".to_owned(), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        }))
+    }
+}
+
+/// The configured webhook subscribers
+#[derive(Clone, Debug, Default)]
+pub struct Webhooks {
+    subscribers: Arc<Vec<Subscriber>>,
+}
+
+impl Webhooks {
+    pub fn from_config(config: &Config) -> HandlerResult<Webhooks> {
+        let table = match config.get_table("webhooks") {
+            Ok(table) => table,
+            Err(ConfigError::Missing(_)) => return Ok(Webhooks::default()),
+            Err(e) => Err(HandlerError::internal(format!(
+                "Invalid ROCKET_WEBHOOKS: {}",
+                e
+            )))?,
+        };
+        let subscribers = table
+            .iter()
+            .map(|(name, value)| Subscriber::from_value(name, value))
+            .collect::<HandlerResult<Vec<_>>>()?;
+        Ok(Webhooks {
+            subscribers: Arc::new(subscribers),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Subscriber> {
+        self.subscribers
+            .iter()
+            .find(|subscriber| subscriber.name == name)
+    }
+
+    /// Queue a new version's delivery to its matching subscribers
+    ///
+    /// Should be called within the transaction storing the version.
+    pub fn enqueue(
+        &self,
+        conn: &TracedConnection,
+        namespace: &str,
+        broadcaster_id: &str,
+        bchannel_id: &str,
+        version: &str,
+        sequence: u64,
+    ) -> HandlerResult<()> {
+        let subscribers: Vec<&str> = self
+            .subscribers
+            .iter()
+            .filter(|subscriber| subscriber.matches(namespace, broadcaster_id, bchannel_id))
+            .map(|subscriber| subscriber.name.as_str())
+            .collect();
+        if subscribers.is_empty() {
+            return Ok(());
+        }
+        WebhookDelivery::enqueue(
+            conn,
+            &subscribers,
+            broadcaster_id,
+            bchannel_id,
+            version,
+            sequence,
+        )
+    }
+}
+
+/// Return the delay before retrying a delivery that's failed `attempts` times
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let exponent = cmp::min(attempts.saturating_sub(1), 16);
+    chrono::Duration::seconds(cmp::min(
+        RETRY_BASE_SECS * 2i64.pow(exponent),
+        RETRY_MAX_SECS,
+    ))
+}
+
+pub struct WebhookDeliverer {
+    webhooks: Webhooks,
+    pool: MysqlPool,
+    log: Logger,
+    metrics: Metrics,
+    interval: Duration,
+    max_attempts: u32,
+    agent: ureq::Agent,
+}
+
+impl WebhookDeliverer {
+    /// Return a WebhookDeliverer, or None if there are no subscribers or
+    /// delivery is disabled
+    pub fn from_config(
+        config: &Config,
+        webhooks: Webhooks,
+        pool: MysqlPool,
+        log: Logger,
+        metrics: Metrics,
+    ) -> HandlerResult<Option<WebhookDeliverer>> {
+        let interval = match config.get_int("webhook_interval") {
+            Ok(interval) if interval >= 0 => interval,
+            Err(ConfigError::Missing(_)) => DEFAULT_WEBHOOK_INTERVAL,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_WEBHOOK_INTERVAL".to_owned(),
+            ))?,
+        };
+        let max_attempts = match config.get_int("webhook_max_attempts") {
+            Ok(max_attempts) if max_attempts > 0 => max_attempts,
+            Err(ConfigError::Missing(_)) => DEFAULT_MAX_ATTEMPTS,
+            _ => Err(HandlerError::internal(
+                "Invalid ROCKET_WEBHOOK_MAX_ATTEMPTS".to_owned(),
+            ))?,
+        };
+        if interval == 0 || webhooks.is_empty() {
+            return Ok(None);
+        }
+        let tls = native_tls::TlsConnector::new()
+            .map_err(|e| HandlerError::internal(format!("Could not initialize TLS: {}", e)))?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(DELIVERY_TIMEOUT)
+            .tls_connector(Arc::new(tls))
+            .build();
+        Ok(Some(WebhookDeliverer {
+            webhooks,
+            pool,
+            log,
+            metrics,
+            interval: Duration::from_secs(interval as u64),
+            max_attempts: max_attempts as u32,
+            agent,
+        }))
+    }
+
+    /// Run the WebhookDeliverer in a background thread until `shutdown`
+    pub fn spawn(self, shutdown: &Shutdown) -> HandlerResult<thread::JoinHandle<()>> {
+        let listener = shutdown.listener();
+        thread::Builder::new()
+            .name("webhooks".to_owned())
+            .spawn(move || {
+                while listener.sleep(self.interval) {
+                    if let Err(e) = self.deliver_due() {
+                        error!(self.log, "Webhook delivery failed: {}", e);
+                    }
+                }
+            })
+            .map_err(|e| {
+                HandlerError::internal(format!("Could not start webhook delivery: {:?}", e))
+            })
+    }
+
+    /// Attempt all due deliveries
+    ///
+    /// A delivery failing to be attempted (or to record its outcome) is
+    /// logged and retried later without affecting the others.
+    ///
+    /// Returns the number of deliveries attempted.
+    pub fn deliver_due(&self) -> HandlerResult<usize> {
+        let mut attempted = 0;
+        loop {
+            let lease_until =
+                Utc::now().naive_utc() + chrono::Duration::seconds(DELIVERY_LEASE_SECS);
+            let claimed = {
+                let conn = self.pool.get()?;
+                WebhookDelivery::claim_due(&conn, DELIVERY_BATCH_SIZE, lease_until)?
+            };
+            let count = claimed.len();
+            for delivery in claimed {
+                if let Err(e) = self.attempt(&delivery) {
+                    error!(
+                        self.log,
+                        "Webhook delivery to: {} for: {} failed: {}",
+                        &delivery.subscriber,
+                        delivery.id(),
+                        e;
+                        "delivery_id" => delivery.id
+                    );
+                    self.retry_failed(&delivery);
+                }
+            }
+            attempted += count;
+            if count < DELIVERY_BATCH_SIZE as usize {
+                return Ok(attempted);
+            }
+        }
+    }
+
+    fn attempt(&self, delivery: &WebhookDelivery) -> HandlerResult<()> {
+        let subscriber = match self.webhooks.get(&delivery.subscriber) {
+            Some(subscriber) => subscriber,
+            None => {
+                warn!(
+                    self.log,
+                    "Dropping webhook delivery to unknown subscriber: {}", &delivery.subscriber;
+                    "delivery_id" => delivery.id
+                );
+                return delivery.remove(&*self.pool.get()?);
+            }
+        };
+        // Continue the trace of the request that queued the delivery
+        let _guard = telemetry::remote_context(delivery.traceparent.as_deref()).attach();
+        let start = Instant::now();
+        let result = telemetry::in_span("webhook.deliver", SpanKind::Client, |_| {
+            self.post(subscriber, delivery)
+        });
+        let mut tags = Tags::default();
+        tags.tags
+            .insert("subscriber".to_owned(), subscriber.name.clone());
+        self.metrics.timer_with_tags(
+            "webhook.delivery_time",
+            (Instant::now() - start).as_millis() as u64,
+            Some(tags.clone()),
+        );
+
+        let conn = self.pool.get()?;
+        let outcome = match result {
+            Ok(()) => {
+                info!(
+                    self.log,
+                    "Delivered webhook to: {} for: {} version: {}",
+                    &subscriber.name,
+                    delivery.id(),
+                    &delivery.version;
+                    "delivery_id" => delivery.id
+                );
+                delivery.remove(&conn)?;
+                "success"
+            }
+            Err(e) if delivery.attempts + 1 >= self.max_attempts => {
+                error!(
+                    self.log,
+                    "Abandoned webhook to: {} for: {} after {} attempts: {}",
+                    &subscriber.name,
+                    delivery.id(),
+                    delivery.attempts + 1,
+                    e;
+                    "delivery_id" => delivery.id
+                );
+                delivery.remove(&conn)?;
+                "abandoned"
+            }
+            Err(e) => {
+                let delay = retry_delay(delivery.attempts + 1);
+                warn!(
+                    self.log,
+                    "Webhook to: {} for: {} failed (retrying in {}s): {}",
+                    &subscriber.name,
+                    delivery.id(),
+                    delay.num_seconds(),
+                    e;
+                    "delivery_id" => delivery.id
+                );
+                delivery.retry_at(&conn, Utc::now().naive_utc() + delay)?;
+                "retry"
+            }
+        };
+        tags.tags.insert("outcome".to_owned(), outcome.to_owned());
+        self.metrics.incr_with_tags("webhook.delivery", Some(tags));
+        Ok(())
+    }
+
+    /// Schedule the retry of a delivery whose attempt failed, rather than
+    /// leaving it leased
+    fn retry_failed(&self, delivery: &WebhookDelivery) {
+        let next_attempt_at = Utc::now().naive_utc() + retry_delay(delivery.attempts + 1);
+        let result = self
+            .pool
+            .get()
+            .map_err(HandlerError::from)
+            .and_then(|conn| delivery.retry_at(&conn, next_attempt_at));
+        if let Err(e) = result {
+            error!(
+                self.log,
+                "Could not reschedule webhook delivery for: {} (retrying after its lease): {}",
+                delivery.id(),
+                e;
+                "delivery_id" => delivery.id
+            );
+        }
+    }
+
+    fn post(&self, subscriber: &Subscriber, delivery: &WebhookDelivery) -> Result<(), String> {
+        let body = serde_json::json!({
+            "id": delivery.id(),
+            "broadcaster_id": delivery.broadcaster_id,
+            "bchannel_id": delivery.bchannel_id,
+            "version": delivery.version,
+            "sequence": delivery.sequence,
+        })
+        .to_string();
+        let signature = subscriber
+            .sign(body.as_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut request = self
+            .agent
+            .post(&subscriber.url)
+            .set("Content-Type", "application/json")
+            .set(SIGNATURE_HEADER, &signature)
+            .set(DELIVERY_HEADER, &delivery.id.to_string());
+        if let Some(traceparent) = telemetry::traceparent() {
+            request = request.set(TRACEPARENT_HEADER, &traceparent);
+        }
+        request
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use rocket::config::{Config, Environment, Value};
+    use std::collections::BTreeMap;
+
+    use super::{retry_delay, Webhooks};
+    use crate::namespace::DEFAULT_NAMESPACE;
+
+    /// A received request: its lowercased header lines and body
+    pub(crate) struct Received {
+        pub headers: Vec<String>,
+        pub body: String,
+    }
+
+    impl Received {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            let prefix = format!("{}: ", name.to_ascii_lowercase());
+            self.headers
+                .iter()
+                .find_map(|line| line.strip_prefix(&prefix))
+        }
+    }
+
+    /// Start a local HTTP stand-in for a webhook subscriber
+    ///
+    /// Responds to each request with the next of `statuses`, returning its
+    /// URL and a Receiver of the requests.
+    pub(crate) fn stand_in(statuses: Vec<u16>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    headers.push(line.to_owned());
+                }
+                // Lowercase the header names
+                let headers: Vec<String> = headers
+                    .into_iter()
+                    .map(|line| match line.split_once(": ") {
+                        Some((name, value)) => {
+                            format!("{}: {}", name.to_ascii_lowercase(), value)
+                        }
+                        None => line,
+                    })
+                    .collect();
+                let len = headers
+                    .iter()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                let body = String::from_utf8(body).unwrap();
+                tx.send(Received { headers, body }).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    pub(crate) fn subscriber(url: &str, secret: &str, channels: Option<Vec<&str>>) -> Value {
+        let mut table = BTreeMap::new();
+        table.insert("url".to_owned(), Value::from(url));
+        table.insert("secret".to_owned(), Value::from(secret));
+        if let Some(channels) = channels {
+            table.insert(
+                "channels".to_owned(),
+                Value::Array(channels.into_iter().map(Value::from).collect()),
+            );
+        }
+        Value::Table(table)
+    }
+
+    fn webhooks(subscribers: Vec<(&str, Value)>) -> Webhooks {
+        let table: BTreeMap<String, Value> = subscribers
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value))
+            .collect();
+        let config = Config::build(Environment::Development)
+            .extra("webhooks", table)
+            .unwrap();
+        Webhooks::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut stage = subscriber("http://c", "s", None);
+        if let Value::Table(table) = &mut stage {
+            table.insert("namespace".to_owned(), Value::from("stage"));
+        }
+        let webhooks = webhooks(vec![
+            ("all", subscriber("http://a", "s", None)),
+            (
+                "some",
+                subscriber("http://b", "s", Some(vec!["foo/*", "baz/quux"])),
+            ),
+            ("stage", stage),
+        ]);
+        let all = webhooks.get("all").unwrap();
+        assert!(all.matches(DEFAULT_NAMESPACE, "foo", "bar"));
+        assert!(!all.matches("stage", "foo", "bar"));
+        let some = webhooks.get("some").unwrap();
+        assert!(some.matches(DEFAULT_NAMESPACE, "foo", "bar"));
+        assert!(some.matches(DEFAULT_NAMESPACE, "baz", "quux"));
+        assert!(!some.matches(DEFAULT_NAMESPACE, "baz", "bar"));
+        assert!(!some.matches(DEFAULT_NAMESPACE, "foobar", "baz"));
+        let stage = webhooks.get("stage").unwrap();
+        assert!(stage.matches("stage", "foo", "bar"));
+        assert!(!stage.matches(DEFAULT_NAMESPACE, "foo", "bar"));
+        assert!(webhooks.get("none").is_none());
+    }
+
+    #[test]
+    fn test_sign() {
+        let webhooks = webhooks(vec![("hook", subscriber("http://a", "key", None))]);
+        // A well known HMAC-SHA256 example
+        assert_eq!(
+            webhooks
+                .get("hook")
+                .unwrap()
+                .sign(b"The quick brown fox jumps over the lazy dog")
+                .unwrap(),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut table = BTreeMap::new();
+        table.insert("url".to_owned(), Value::from("http://a"));
+        let config = Config::build(Environment::Development)
+            .extra("webhooks", {
+                let mut webhooks = BTreeMap::new();
+                webhooks.insert("nosecret".to_owned(), Value::Table(table));
+                webhooks
+            })
+            .unwrap();
+        assert!(Webhooks::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1).num_seconds(), 2);
+        assert_eq!(retry_delay(2).num_seconds(), 4);
+        assert_eq!(retry_delay(5).num_seconds(), 32);
+        assert_eq!(retry_delay(100).num_seconds(), 60 * 60);
+    }
+}